@@ -14,6 +14,15 @@
 
 //! Mining Stratum Pool
 
+// The code base keeps explicit returns, field: field initializers, 'static
+// consts and the max_value() constructors of older Rust
+#![allow(clippy::needless_return)]
+#![allow(clippy::redundant_field_names)]
+#![allow(clippy::redundant_static_lifetimes)]
+#![allow(clippy::legacy_numeric_constants)]
+#![allow(clippy::too_many_arguments)]
+#![allow(clippy::module_inception)]
+
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -27,25 +36,21 @@ extern crate slog_term;
 extern crate time;
 #[macro_use]
 extern crate lazy_static;
-#[macro_use]
-extern crate toml;
 extern crate sha2;
+extern crate toml;
 #[macro_use]
 extern crate error_chain;
 extern crate base64;
 extern crate bincode;
+extern crate blake2_rfc;
+extern crate byteorder;
 extern crate chrono;
 extern crate kafka;
-
-use bufstream::BufStream;
-use std::error::Error;
-use std::io::BufRead;
-use std::io::{ErrorKind, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex, RwLock};
-use std::thread;
-use std::time::Duration;
-use std::time::SystemTime;
+extern crate mio;
+extern crate rustc_serialize;
+extern crate rustls;
+extern crate webpki;
+extern crate webpki_roots;
 
 mod pool;
 use pool::config;
@@ -57,7 +62,7 @@ fn main() {
 
     let config = config::read_config();
 
-    println!("{:?}", config);
+    debug!(LOGGER, "{:?}", config);
 
     let mut my_pool = Pool::new(config);
     my_pool.run();
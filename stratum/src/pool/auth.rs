@@ -0,0 +1,287 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Miner Login Authorization
+//!
+//! Resolves the account a miner logs in with to a user id through the pool
+//! API.  The endpoint is POSTed a JSON object:
+//!
+//!     {"login": "username", "password": "x", "create": false}
+//!
+//! The password is only sent when it is checked, and create asks for an
+//! unknown user to be created.  The endpoint answers 200 with
+//! {"user_id": 123}, 404 for an unknown user, or 401 / 403 for a wrong
+//! password.  Lookups run on background threads and results are cached.
+//! When the endpoint fails, or too many lookups are waiting, a login is
+//! unavailable rather than refused, so miners are not blamed for it.
+//!
+
+use serde_json;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pool::config::AuthConfig;
+use pool::http;
+use pool::logger::LOGGER;
+
+// Lookups that may run at once
+const AUTH_THREADS: usize = 4;
+// Seconds to remember a lookup result
+const DEFAULT_CACHE_TTL: u64 = 300;
+// Seconds to wait for the endpoint
+const DEFAULT_TIMEOUT: u64 = 10;
+// Seconds to remember that the endpoint failed, so waiting miners get an answer
+const ERROR_CACHE_TTL: u64 = 5;
+// Lookups that may wait for a thread, further logins are asked to try again
+const MAX_PENDING: usize = 1000;
+
+// A lookup is cached by login, and by password when it is checked
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    login: String,
+    password: Vec<u8>, // SHA-256 of the password, empty if not checked
+}
+
+/// The outcome of a lookup
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthResult {
+    Ok(i32),         // the user id
+    Refused(String), // the reason the login is refused
+    Unavailable,     // the endpoint did not answer, try again later
+}
+
+struct Cached {
+    result: AuthResult,
+    expires: Instant,
+}
+
+type Response = Result<(u16, String), String>;
+
+pub struct Authorizer {
+    config: AuthConfig,
+    cache: HashMap<Key, Cached>,
+    pending: HashSet<Key>, // lookups in progress
+    requests: Sender<(Key, String)>,
+    results: Receiver<(Key, Response)>,
+}
+
+impl Authorizer {
+    pub fn new(config: AuthConfig) -> Authorizer {
+        let (request_tx, request_rx) = channel::<(Key, String)>();
+        let (result_tx, result_rx) = channel();
+        let request_rx = Arc::new(Mutex::new(request_rx));
+        let timeout = Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        for _ in 0..AUTH_THREADS {
+            let request_rx = request_rx.clone();
+            let result_tx = result_tx.clone();
+            let url = config.url.clone();
+            thread::spawn(move || loop {
+                let request = match request_rx.lock() {
+                    Ok(rx) => rx.recv(),
+                    Err(_) => return,
+                };
+                let (key, body) = match request {
+                    Ok(request) => request,
+                    Err(_) => return, // The pool is gone
+                };
+                let response = http::post_json(&url, &body, timeout);
+                if result_tx.send((key, response)).is_err() {
+                    return;
+                }
+            });
+        }
+        Authorizer {
+            config: config,
+            cache: HashMap::new(),
+            pending: HashSet::new(),
+            requests: request_tx,
+            results: result_rx,
+        }
+    }
+
+    /// The user id for an account, or the reason it is refused
+    /// Returns None while the lookup is in progress, call again after poll()
+    pub fn authorize(&mut self, login: &str, password: &str) -> Option<AuthResult> {
+        let check_password = self.config.check_password.unwrap_or(false);
+        let key = Key {
+            login: login.to_string(),
+            password: if check_password {
+                Sha256::digest(password.as_bytes()).to_vec()
+            } else {
+                Vec::new()
+            },
+        };
+        if let Some(cached) = self.cache.get(&key) {
+            if cached.expires > Instant::now() {
+                return Some(cached.result.clone());
+            }
+        }
+        if self.pending.contains(&key) {
+            return None;
+        }
+        if self.pending.len() >= MAX_PENDING {
+            return Some(AuthResult::Unavailable);
+        }
+        let mut body = json!({
+            "login": login,
+            "create": self.config.unknown_users.as_deref() == Some("create"),
+        });
+        if check_password {
+            body["password"] = Value::String(password.to_string());
+        }
+        match self.requests.send((key.clone(), body.to_string())) {
+            Ok(_) => {
+                self.pending.insert(key);
+                None
+            }
+            Err(_) => Some(AuthResult::Unavailable),
+        }
+    }
+
+    /// Collect finished lookups, returns true if any finished
+    pub fn poll(&mut self) -> bool {
+        let mut finished = false;
+        while let Ok((key, response)) = self.results.try_recv() {
+            finished = true;
+            self.pending.remove(&key);
+            let (result, ttl) = match response.and_then(|(status, body)| interpret(status, &body)) {
+                Ok(result) => (result, self.config.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL)),
+                Err(e) => {
+                    error!(
+                        LOGGER,
+                        "Unable to authorize login {} with {}: {}", key.login, self.config.url, e
+                    );
+                    if self.config.allow_on_error.unwrap_or(false) {
+                        (AuthResult::Ok(0), ERROR_CACHE_TTL)
+                    } else {
+                        (AuthResult::Unavailable, ERROR_CACHE_TTL)
+                    }
+                }
+            };
+            self.cache.insert(
+                key,
+                Cached {
+                    result: result,
+                    expires: Instant::now() + Duration::from_secs(ttl),
+                },
+            );
+        }
+        finished
+    }
+
+    /// Forget expired results
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.cache.retain(|_, cached| cached.expires > now);
+    }
+}
+
+// The user id or the reason the login is refused, or an error if the
+// endpoint did not give an answer
+fn interpret(status: u16, body: &str) -> Result<AuthResult, String> {
+    match status {
+        200 => {
+            let response: Value =
+                serde_json::from_str(body).map_err(|e| format!("Invalid response: {}", e))?;
+            match response["user_id"].as_i64() {
+                Some(user_id) if user_id >= 0 && user_id <= i32::max_value() as i64 => {
+                    Ok(AuthResult::Ok(user_id as i32))
+                }
+                Some(user_id) => Err(format!("Invalid user_id {}", user_id)),
+                None => Err("Response has no user_id".to_string()),
+            }
+        }
+        404 => Ok(AuthResult::Refused("Unknown user".to_string())),
+        401 | 403 => Ok(AuthResult::Refused("Invalid password".to_string())),
+        status => Err(format!("Request failed with status {}", status)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_interpret() {
+        assert_eq!(interpret(200, "{\"user_id\": 42}"), Ok(AuthResult::Ok(42)));
+        assert_eq!(
+            interpret(404, ""),
+            Ok(AuthResult::Refused("Unknown user".to_string()))
+        );
+        assert_eq!(
+            interpret(403, ""),
+            Ok(AuthResult::Refused("Invalid password".to_string()))
+        );
+        assert!(interpret(200, "{}").is_err());
+        // A user id that does not fit the share record
+        assert!(interpret(200, "{\"user_id\": 4294967301}").is_err());
+        assert!(interpret(200, "{\"user_id\": -1}").is_err());
+        assert!(interpret(200, "not json").is_err());
+        assert!(interpret(500, "").is_err());
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut auth = Authorizer::new(AuthConfig {
+            url: "http://127.0.0.1:1/login".to_string(),
+            check_password: Some(true),
+            unknown_users: None,
+            cache_ttl: None,
+            timeout: None,
+            allow_on_error: None,
+        });
+        let key = Key {
+            login: "user".to_string(),
+            password: Sha256::digest(b"secret").to_vec(),
+        };
+        auth.cache.insert(
+            key,
+            Cached {
+                result: AuthResult::Ok(7),
+                expires: Instant::now() + Duration::from_secs(60),
+            },
+        );
+        assert_eq!(auth.authorize("user", "secret"), Some(AuthResult::Ok(7)));
+        // A different password is looked up
+        assert_eq!(auth.authorize("user", "guess"), None);
+        assert_eq!(auth.authorize("user", "guess"), None);
+        assert_eq!(auth.pending.len(), 1);
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let mut auth = Authorizer::new(AuthConfig {
+            url: "http://127.0.0.1:1/login".to_string(),
+            check_password: None,
+            unknown_users: None,
+            cache_ttl: None,
+            timeout: None,
+            allow_on_error: None,
+        });
+        for n in 0..MAX_PENDING {
+            auth.pending.insert(Key {
+                login: n.to_string(),
+                password: Vec::new(),
+            });
+        }
+        assert_eq!(auth.authorize("user", "x"), Some(AuthResult::Unavailable));
+        // A lookup already in progress is still waited for
+        assert_eq!(auth.authorize("0", "x"), None);
+        assert_eq!(auth.pending.len(), MAX_PENDING);
+    }
+}
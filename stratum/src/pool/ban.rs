@@ -0,0 +1,341 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Banned IP Addresses
+//!
+//! Bans are single addresses or CIDR ranges, IPv4 or IPv6, with an optional
+//! expiry time.  The list is read from a file and from an HTTP endpoint, one
+//! ban per line:
+//!
+//!     10.1.2.3
+//!     192.168.0.0/16 1546300800
+//!     2001:db8::/32
+//!
+//! The second field is a unix timestamp after which the ban no longer applies.
+//! Blank lines and lines starting with # are ignored.
+//!
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use pool::config::BanConfig;
+use pool::http;
+use pool::logger::LOGGER;
+
+// Default seconds between reloading the ban list
+const DEFAULT_REFRESH_INTERVAL: u64 = 60;
+// Seconds to wait for the ban list endpoint
+const FETCH_TIMEOUT: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    network: IpAddr,
+    prefix: u8,
+    expires: Option<SystemTime>,
+}
+
+impl Ban {
+    /// Parse one line of a ban list: "address[/prefix] [expiry]"
+    pub fn parse(line: &str) -> Result<Ban, String> {
+        let mut fields = line.split_whitespace();
+        let range = match fields.next() {
+            Some(range) => range,
+            None => return Err("Empty ban".to_string()),
+        };
+        let (address, prefix) = match range.find('/') {
+            Some(idx) => (&range[..idx], Some(&range[idx + 1..])),
+            None => (range, None),
+        };
+        let address = match address.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return Err(format!("{} - Invalid address", range)),
+        };
+        let max_prefix = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let mut prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => return Err(format!("{} - Invalid prefix length", range)),
+            },
+            None => max_prefix,
+        };
+        let network = normalize(address);
+        if address.is_ipv6() && network.is_ipv4() {
+            // An IPv4-mapped range, the prefix covers the leading 96 bits
+            if prefix < 96 {
+                return Err(format!("{} - Invalid prefix length", range));
+            }
+            prefix -= 96;
+        }
+        let expires = match fields.next() {
+            Some(expiry) => match expiry.parse::<u64>() {
+                Ok(secs) => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+                Err(_) => return Err(format!("{} - Invalid expiry time", expiry)),
+            },
+            None => None,
+        };
+        Ok(Ban {
+            network: network,
+            prefix: prefix,
+            expires: expires,
+        })
+    }
+
+    /// Is the address within the banned range
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, normalize(*ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = if self.prefix == 0 {
+                    0
+                } else {
+                    !0u32 << (32 - self.prefix)
+                };
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = if self.prefix == 0 {
+                    0
+                } else {
+                    !0u128 << (128 - self.prefix)
+                };
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    pub fn expired(&self, now: SystemTime) -> bool {
+        match self.expires {
+            Some(expires) => expires <= now,
+            None => false,
+        }
+    }
+}
+
+// Treat IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) as IPv4
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            if s[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                IpAddr::V4(Ipv4Addr::new(
+                    (s[6] >> 8) as u8,
+                    s[6] as u8,
+                    (s[7] >> 8) as u8,
+                    s[7] as u8,
+                ))
+            } else {
+                IpAddr::V6(v6)
+            }
+        }
+        ip => ip,
+    }
+}
+
+/// Parse a ban list, returns the valid bans and the errors for lines that are not
+pub fn parse_bans(text: &str) -> (Vec<Ban>, Vec<String>) {
+    let mut bans = Vec::new();
+    let mut errors = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+        match Ban::parse(line) {
+            Ok(ban) => bans.push(ban),
+            Err(e) => errors.push(e),
+        }
+    }
+    (bans, errors)
+}
+
+// Parse a ban list, logging and skipping lines that are not valid
+fn load_bans(source: &str, text: &str) -> Vec<Ban> {
+    let (bans, errors) = parse_bans(text);
+    for e in errors {
+        warn!(LOGGER, "{} - Skipping ban: {}", source, e);
+    }
+    bans
+}
+
+pub struct BanList {
+    config: BanConfig,
+    file_bans: Vec<Ban>,
+    url_bans: Vec<Ban>,
+    next_refresh: Instant,
+    fetch: Option<Receiver<Result<String, String>>>, // ban list request in progress
+}
+
+impl BanList {
+    pub fn new(config: Option<BanConfig>) -> BanList {
+        BanList {
+            config: config.unwrap_or(BanConfig {
+                file: None,
+                url: None,
+                refresh_interval: None,
+            }),
+            file_bans: Vec::new(),
+            url_bans: Vec::new(),
+            next_refresh: Instant::now(),
+            fetch: None,
+        }
+    }
+
+    /// Is this address banned
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        let now = SystemTime::now();
+        self.file_bans
+            .iter()
+            .chain(self.url_bans.iter())
+            .any(|ban| !ban.expired(now) && ban.contains(ip))
+    }
+
+    /// Reload the ban file and start fetching the url when due, and pick up a
+    /// completed fetch.  Returns true if the list changed.
+    /// The request runs on its own thread so it never blocks the event loop.
+    pub fn refresh(&mut self) -> bool {
+        let mut changed = self.receive();
+        if Instant::now() < self.next_refresh {
+            return changed;
+        }
+        let interval = self
+            .config
+            .refresh_interval
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL);
+        self.next_refresh = Instant::now() + Duration::from_secs(interval);
+        if let Some(path) = self.config.file.clone() {
+            let mut text = String::new();
+            match File::open(&path).and_then(|mut file| file.read_to_string(&mut text)) {
+                Ok(_) => {
+                    let bans = load_bans(&path, &text);
+                    if bans != self.file_bans {
+                        self.file_bans = bans;
+                        changed = true;
+                    }
+                }
+                Err(e) => {
+                    // Keep the bans we already have
+                    error!(LOGGER, "{} - Unable to read ban list: {}", path, e);
+                }
+            }
+        }
+        if let Some(url) = self.config.url.clone() {
+            if self.fetch.is_none() {
+                let (tx, rx) = channel();
+                thread::spawn(move || {
+                    let _ = tx.send(http::get(&url, Duration::from_secs(FETCH_TIMEOUT)));
+                });
+                self.fetch = Some(rx);
+            }
+        }
+        changed
+    }
+
+    // Check for a completed ban list request
+    fn receive(&mut self) -> bool {
+        let result = match self.fetch {
+            Some(ref rx) => match rx.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => Err("Request failed".to_string()),
+            },
+            None => return false,
+        };
+        self.fetch = None;
+        let url = self.config.url.clone().unwrap_or_default();
+        match result {
+            Ok(text) => {
+                let bans = load_bans(&url, &text);
+                if bans != self.url_bans {
+                    self.url_bans = bans;
+                    return true;
+                }
+            }
+            Err(e) => {
+                // Keep the bans we already have
+                error!(LOGGER, "{} - Unable to fetch ban list: {}", url, e);
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ipv4_ranges() {
+        let ban = Ban::parse("192.168.0.0/16").unwrap();
+        assert!(ban.contains(&ip("192.168.1.2")));
+        assert!(ban.contains(&ip("::ffff:192.168.1.2")));
+        assert!(!ban.contains(&ip("192.169.0.1")));
+        assert!(!ban.contains(&ip("::1")));
+
+        let ban = Ban::parse("10.1.2.3").unwrap();
+        assert!(ban.contains(&ip("10.1.2.3")));
+        assert!(!ban.contains(&ip("10.1.2.4")));
+
+        let ban = Ban::parse("0.0.0.0/0").unwrap();
+        assert!(ban.contains(&ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn test_ipv6_ranges() {
+        let ban = Ban::parse("2001:db8::/32").unwrap();
+        assert!(ban.contains(&ip("2001:db8:1::1")));
+        assert!(!ban.contains(&ip("2001:db9::1")));
+        assert!(!ban.contains(&ip("32.1.13.184")));
+
+        let ban = Ban::parse("::ffff:10.0.0.0/104").unwrap();
+        assert!(ban.contains(&ip("10.20.30.40")));
+    }
+
+    #[test]
+    fn test_invalid_bans() {
+        assert!(Ban::parse("10.0.0.0/33").is_err());
+        assert!(Ban::parse("2001:db8::/129").is_err());
+        assert!(Ban::parse("example.com").is_err());
+        assert!(Ban::parse("10.0.0.1 tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let ban = Ban::parse("10.0.0.1 1000").unwrap();
+        assert!(ban.expired(UNIX_EPOCH + Duration::from_secs(1000)));
+        assert!(!ban.expired(UNIX_EPOCH + Duration::from_secs(999)));
+        assert!(!Ban::parse("10.0.0.1").unwrap().expired(SystemTime::now()));
+    }
+
+    #[test]
+    fn test_ban_list() {
+        let mut bans = BanList::new(None);
+        let (file_bans, errors) = parse_bans("# comment\n\n10.0.0.0/8\nbogus\n192.168.1.1 1000\n");
+        assert_eq!(file_bans.len(), 2);
+        assert_eq!(errors.len(), 1);
+        bans.file_bans = file_bans;
+        assert!(bans.is_banned(&ip("10.9.8.7")));
+        // Expired
+        assert!(!bans.is_banned(&ip("192.168.1.1")));
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Newline Delimited Message Codec
+//!
+//! Frames the JSON RPC messages exchanged with workers and upstream servers.
+//! Partial messages are kept across reads on a nonblocking stream, and a
+//! message longer than the maximum size is an error.
+//! Outgoing messages are queued and written as the stream accepts them.
+//!
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+
+// Default largest message we accept, in bytes
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024;
+// Default number of messages that may wait to be written
+pub const DEFAULT_MAX_QUEUED_MESSAGES: usize = 64;
+// Bytes to read from the stream at a time
+const READ_SIZE: usize = 4096;
+
+pub struct LineCodec {
+    buffer: Vec<u8>,
+    max_size: usize,
+}
+
+impl LineCodec {
+    pub fn new(max_size: usize) -> LineCodec {
+        LineCodec {
+            buffer: Vec::new(),
+            max_size: max_size,
+        }
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    /// Read the next complete message from the stream, without its line ending
+    /// Returns None when the stream has no complete message ready
+    pub fn read_message<R: Read>(&mut self, stream: &mut R) -> Result<Option<String>, String> {
+        loop {
+            // Return a message we already have
+            if let Some(message) = self.decode()? {
+                return Ok(Some(message));
+            }
+            let mut chunk = [0u8; READ_SIZE];
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err("Connection Error: Disconnected".to_string());
+                }
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    // Not an error, just no complete message ready
+                    return Ok(None);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(format!("Connection Error: {}", e));
+                }
+            }
+        }
+    }
+
+    /// Take the next complete message out of the buffer
+    fn decode(&mut self) -> Result<Option<String>, String> {
+        loop {
+            match self.buffer.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    if end > self.max_size {
+                        return Err(format!(
+                            "Message of {} bytes is larger than the {} byte limit",
+                            end, self.max_size
+                        ));
+                    }
+                    let line: Vec<u8> = self.buffer.drain(..end + 1).collect();
+                    let message = String::from_utf8_lossy(&line).trim().to_string();
+                    // Skip blank lines
+                    if !message.is_empty() {
+                        return Ok(Some(message));
+                    }
+                }
+                None => {
+                    if self.buffer.len() > self.max_size {
+                        return Err(format!(
+                            "Message larger than the {} byte limit",
+                            self.max_size
+                        ));
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Frame a message for writing to the stream
+    pub fn encode(message: &str) -> Vec<u8> {
+        let mut frame = message.as_bytes().to_vec();
+        if !message.ends_with("\n") {
+            frame.push(b'\n');
+        }
+        frame
+    }
+}
+
+// A message waiting to be written
+struct Frame {
+    data: Vec<u8>,
+    job: bool, // A job is superseded by the next one
+}
+
+pub struct WriteQueue {
+    frames: VecDeque<Frame>,
+    current: Vec<u8>, // The message being written
+    written: usize,   // Bytes of the current message already written
+    max_frames: usize,
+}
+
+impl WriteQueue {
+    pub fn new(max_frames: usize) -> WriteQueue {
+        WriteQueue {
+            frames: VecDeque::new(),
+            current: Vec::new(),
+            written: 0,
+            max_frames: max_frames,
+        }
+    }
+
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    /// Nothing left to write
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty() && self.written == self.current.len()
+    }
+
+    /// Queue a message, a job replaces any queued job not yet started
+    pub fn push(&mut self, message: &str, job: bool) -> Result<(), String> {
+        if job {
+            self.frames.retain(|frame| !frame.job);
+        }
+        if self.frames.len() >= self.max_frames {
+            return Err(format!(
+                "Outbound queue full with {} messages",
+                self.frames.len()
+            ));
+        }
+        self.frames.push_back(Frame {
+            data: LineCodec::encode(message),
+            job: job,
+        });
+        Ok(())
+    }
+
+    /// Write queued messages until the stream would block or the queue is empty
+    pub fn flush<W: Write>(&mut self, stream: &mut W) -> Result<(), String> {
+        loop {
+            if self.written == self.current.len() {
+                match self.frames.pop_front() {
+                    Some(frame) => {
+                        self.current = frame.data;
+                        self.written = 0;
+                    }
+                    None => {
+                        return stream
+                            .flush()
+                            .map_err(|e| format!("Connection Error: {}", e))
+                    }
+                }
+            }
+            match stream.write(&self.current[self.written..]) {
+                Ok(0) => {
+                    return Err("Connection Error: Write returned zero".to_string());
+                }
+                Ok(n) => {
+                    self.written += n;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    // The rest is written when the stream becomes writable
+                    return Ok(());
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(format!("Connection Error: {}", e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    // A nonblocking stream that returns the given reads, then WouldBlock
+    struct MockStream {
+        reads: Vec<Vec<u8>>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.reads.is_empty() {
+                return Err(io::Error::new(ErrorKind::WouldBlock, "would block"));
+            }
+            let data = self.reads.remove(0);
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        }
+    }
+
+    #[test]
+    fn test_partial_reads() {
+        let mut codec = LineCodec::new(DEFAULT_MAX_MESSAGE_SIZE);
+        let mut stream = MockStream {
+            reads: vec![b"{\"id\":1,".to_vec()],
+        };
+        assert_eq!(codec.read_message(&mut stream), Ok(None));
+        stream
+            .reads
+            .push(b"\"method\":\"login\"}\r\n{\"id\":2}\n\n{\"id".to_vec());
+        assert_eq!(
+            codec.read_message(&mut stream),
+            Ok(Some("{\"id\":1,\"method\":\"login\"}".to_string()))
+        );
+        assert_eq!(
+            codec.read_message(&mut stream),
+            Ok(Some("{\"id\":2}".to_string()))
+        );
+        assert_eq!(codec.read_message(&mut stream), Ok(None));
+        stream.reads.push(b"\":3}\n".to_vec());
+        assert_eq!(
+            codec.read_message(&mut stream),
+            Ok(Some("{\"id\":3}".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_oversize_message() {
+        let mut codec = LineCodec::new(16);
+        let mut stream = MockStream {
+            reads: vec![b"0123456789".to_vec()],
+        };
+        assert_eq!(codec.read_message(&mut stream), Ok(None));
+        // No newline and over the limit
+        stream.reads.push(b"0123456789".to_vec());
+        assert!(codec.read_message(&mut stream).is_err());
+
+        let mut codec = LineCodec::new(16);
+        let mut stream = MockStream {
+            reads: vec![b"01234567890123456789\n".to_vec()],
+        };
+        assert!(codec.read_message(&mut stream).is_err());
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut codec = LineCodec::new(DEFAULT_MAX_MESSAGE_SIZE);
+        let mut stream = MockStream {
+            reads: vec![Vec::new()],
+        };
+        assert!(codec.read_message(&mut stream).is_err());
+    }
+
+    // A nonblocking stream that accepts a limited number of bytes
+    struct MockSink {
+        written: Vec<u8>,
+        space: usize,
+    }
+
+    impl Write for MockSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.space == 0 {
+                return Err(io::Error::new(ErrorKind::WouldBlock, "would block"));
+            }
+            let n = ::std::cmp::min(self.space, buf.len());
+            self.written.extend_from_slice(&buf[..n]);
+            self.space -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_queue() {
+        let mut queue = WriteQueue::new(3);
+        let mut sink = MockSink {
+            written: Vec::new(),
+            space: 4,
+        };
+        queue.push("job1", true).unwrap();
+        queue.push("ok", false).unwrap();
+        // Partially written, the rest waits for the stream
+        queue.flush(&mut sink).unwrap();
+        assert_eq!(sink.written, b"job1".to_vec());
+        assert!(!queue.is_empty());
+        // Only the latest queued job is sent
+        queue.push("job2", true).unwrap();
+        queue.push("job3", true).unwrap();
+        sink.space = 100;
+        queue.flush(&mut sink).unwrap();
+        assert_eq!(sink.written, b"job1\nok\njob3\n".to_vec());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_write_queue_overflow() {
+        let mut queue = WriteQueue::new(2);
+        queue.push("a", false).unwrap();
+        queue.push("b", false).unwrap();
+        assert!(queue.push("c", false).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(LineCodec::encode("{}"), b"{}\n".to_vec());
+        assert_eq!(LineCodec::encode("{}\n"), b"{}\n".to_vec());
+    }
+}
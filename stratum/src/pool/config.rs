@@ -11,40 +11,58 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-use serde_derive;
 use std::collections::HashMap;
+use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use toml;
 
+use pool::pow::{MAX_PRIMARY_EDGE_BITS, MIN_PRIMARY_EDGE_BITS, SECONDARY_EDGE_BITS};
+
 const CONFIG_FILE_NAME: &'static str = ".grin-pool.toml";
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub grin_pool: PoolConfig,
     pub grin_node: NodeConfig,
+    #[serde(default)]
+    pub grin_nodes: Vec<NodeConfig>, // Additional upstream nodes to fail over to
     pub workers: WorkerConfig,
     pub producer: ProducerConfig,
     pub server: ServerConfig,
+    pub bans: Option<BanConfig>,
+    pub reputation: Option<ReputationConfig>, // automatic bans, disabled without this section
+    pub auth: Option<AuthConfig>, // login authorization, any login is accepted without this section
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PortDifficulty {
     pub port: u64,
     pub difficulty: u64,
+    #[serde(default)]
+    pub min_difficulty: Option<u64>, // vardiff lower bound, defaults to difficulty
+    #[serde(default)]
+    pub max_difficulty: Option<u64>, // vardiff upper bound, defaults to unbounded
+    #[serde(default)]
+    pub max_connections: Option<usize>, // open connections allowed on this port
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct VarDiffConfig {
+    pub target_shares_per_minute: f64,
+    pub retarget_time: u64, // seconds
+    pub variance: f64,      // allowed deviation from the target before retargeting, 0.3 = 30%
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
     pub id: u16,
+    pub job_timeout: Option<u64>, // seconds without a new job before failing over
+    pub keepalive_interval: Option<u64>, // seconds between upstream keepalive / status requests
+    pub response_timeout: Option<u64>, // seconds to wait for a keepalive response
+    pub height_timeout: Option<u64>, // seconds the job height may stay the same
+    pub submit_timeout: Option<u64>, // seconds to wait for a submit response
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -56,31 +74,216 @@ pub struct PoolConfig {
 pub struct WorkerConfig {
     pub listen_address: String,
     pub port_difficulty: Vec<PortDifficulty>,
+    pub vardiff: Option<VarDiffConfig>,
+    pub max_strikes: Option<u32>, // bad requests allowed before a worker is disconnected
+    pub max_message_size: Option<usize>, // largest request accepted from a worker, in bytes
+    pub max_queued_messages: Option<usize>, // messages waiting to be written before a worker is dropped
+    #[serde(default)]
+    pub tls_port_difficulty: Vec<PortDifficulty>, // listen ports for TLS connections
+    pub tls_certificate: Option<String>,    // PEM certificate chain for the TLS ports
+    pub tls_private_key: Option<String>,    // PEM private key for the TLS ports
+    pub max_connections: Option<usize>,     // open worker connections allowed in total
+    pub max_connections_per_ip: Option<usize>, // open connections allowed from one address
+    pub max_connections_per_login: Option<usize>, // logged in connections allowed per account, across its workers
+    pub connection_rate: Option<f64>,             // new connections accepted per second
+    pub connection_burst: Option<f64>,            // new connections accepted at once above the rate
+    pub login_timeout: Option<u64>,               // seconds to log in after connecting, 0 = forever
+    pub idle_timeout: Option<u64>,                // seconds allowed between messages, 0 = forever
+    pub share_timeout: Option<u64>, // seconds allowed without a valid share, 0 = forever
+    pub max_messages_per_pass: Option<usize>, // requests handled per worker each event loop turn
+    pub rate_limits: Option<RateLimitConfig>,
+    pub naming: Option<NamingConfig>,
+    pub miner_options: Option<MinerOptionsConfig>, // options in the password field are ignored without this
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimit {
+    pub rate: f64,  // requests per second
+    pub burst: f64, // requests allowed at once above the rate
+}
+
+// Per worker session request limits, unlimited if not set
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    pub submit: Option<RateLimit>,
+    pub status: Option<RateLimit>,
+    pub job: Option<RateLimit>, // getjobtemplate
+}
+
+// Limits on the options miners send in their password field
+#[derive(Debug, Deserialize, Clone)]
+pub struct MinerOptionsConfig {
+    pub min_difficulty: Option<u64>, // lowest difficulty a miner may ask for
+    pub max_difficulty: Option<u64>, // highest difficulty a miner may ask for
+    pub allow_fixed_difficulty: Option<bool>, // may miners turn vardiff off
+    pub edge_bits: Option<Vec<u32>>, // edge_bits miners may ask for, any by default
 }
 
+// Rules for worker login names, "account.worker"
 #[derive(Debug, Deserialize, Clone)]
+pub struct NamingConfig {
+    pub separators: Option<String>, // characters that end the account name
+    pub min_account_length: Option<usize>,
+    pub max_account_length: Option<usize>,
+    pub max_worker_length: Option<usize>,
+    pub account_chars: Option<String>, // allowed characters, with ranges such as "a-z0-9_"
+    pub worker_chars: Option<String>,
+    pub case: Option<String>,           // "lower", "upper" or "preserve"
+    pub default_worker: Option<String>, // worker name for logins without one, "" to require one
+    pub wallet_addresses: Option<bool>, // accept a grin wallet address as the account
+    pub email_logins: Option<bool>,     // accept an email address as the account
+}
+
+// Debug is written by hand so the password is never logged
+#[derive(Deserialize, Clone)]
 pub struct NodeConfig {
     pub address: String,
     pub api_port: u64,
     pub stratum_port: u64,
     pub login: String,
+    #[serde(default)]
     pub password: String,
+    pub password_file: Option<String>, // read the password from this file instead
+    pub password_env: Option<String>,  // read the password from this environment variable instead
+    #[serde(default)]
+    pub priority: u32, // Lower is preferred
+    #[serde(default)]
+    pub tls: bool, // connect to the stratum port with TLS
+    pub tls_ca_file: Option<String>, // PEM CA bundle to verify the node, instead of the public roots
+    pub tls_pin: Option<String>,     // SHA-256 fingerprint of the nodes certificate, hex
+    pub tls_server_name: Option<String>, // name in the nodes certificate, defaults to address
+}
+
+impl fmt::Debug for NodeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NodeConfig")
+            .field("address", &self.address)
+            .field("api_port", &self.api_port)
+            .field("stratum_port", &self.stratum_port)
+            .field("login", &self.login)
+            .field("password", &"<redacted>")
+            .field("password_file", &self.password_file)
+            .field("password_env", &self.password_env)
+            .field("priority", &self.priority)
+            .field("tls", &self.tls)
+            .field("tls_ca_file", &self.tls_ca_file)
+            .field("tls_pin", &self.tls_pin)
+            .field("tls_server_name", &self.tls_server_name)
+            .finish()
+    }
+}
+
+impl NodeConfig {
+    /// Find the upstream password, from the environment, a file, or the config itself
+    pub fn load_password(&self) -> Result<String, String> {
+        if let Some(ref name) = self.password_env {
+            return env::var(name).map_err(|e| format!("{} - {}", name, e));
+        }
+        if let Some(ref path) = self.password_file {
+            let mut password = String::new();
+            File::open(path)
+                .and_then(|mut file| file.read_to_string(&mut password))
+                .map_err(|e| format!("{} - {}", path, e))?;
+            return Ok(password.trim().to_string());
+        }
+        Ok(self.password.clone())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BanConfig {
+    pub file: Option<String>, // one IP or CIDR range per line, with an optional unix expiry time
+    pub url: Option<String>,  // http endpoint returning the same format, such as the pool API
+    pub refresh_interval: Option<u64>, // seconds between reloading the file and url
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReputationConfig {
+    pub window: Option<u64>,     // seconds over which misbehaviour is counted
+    pub min_shares: Option<u64>, // shares needed before the invalid ratio is judged
+    pub max_invalid_ratio: Option<f64>, // invalid shares / all shares, 0.5 = 50%
+    pub max_duplicates: Option<u64>, // duplicate shares per window
+    pub max_malformed: Option<u64>, // malformed or unknown requests per window
+    pub max_connects: Option<u64>, // new connections from one IP per window
+    pub max_login_failures: Option<u64>, // failed logins per window
+    pub max_rate_limited: Option<u64>, // requests over the rate limits per window
+    pub ban_time: Option<u64>,   // seconds for a first ban, doubled for each repeat
+    pub max_ban_time: Option<u64>, // longest ban, seconds
+    pub forget_after: Option<u64>, // seconds without activity before past bans are forgotten
+    pub max_records: Option<usize>, // addresses and accounts tracked at once
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuthConfig {
+    pub url: String, // pool API endpoint that resolves a login to a user id
+    pub check_password: Option<bool>, // send the miners password to be checked
+    pub unknown_users: Option<String>, // "reject" or "create"
+    pub cache_ttl: Option<u64>, // seconds to remember a lookup
+    pub timeout: Option<u64>, // seconds to wait for the endpoint
+    pub allow_on_error: Option<bool>, // accept logins, as user 0, while the endpoint is failing
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ProducerConfig {
     pub brokers: Vec<String>,
     pub topics: HashMap<String, String>,
-    pub partitions: i32,
     pub options: Option<HashMap<String, String>>,
 }
 
+impl Config {
+    /// All upstream grin nodes, most preferred first
+    pub fn upstream_nodes(&self) -> Vec<NodeConfig> {
+        let mut nodes = vec![self.grin_node.clone()];
+        nodes.extend(self.grin_nodes.iter().cloned());
+        nodes.sort_by_key(|node| node.priority);
+        nodes
+    }
+
+    /// Check that every share size the pool accepts has a producer topic
+    pub fn check_share_topics(&self) -> Result<(), String> {
+        let mut edge_bits: Vec<u32> = vec![SECONDARY_EDGE_BITS];
+        edge_bits.extend(MIN_PRIMARY_EDGE_BITS..MAX_PRIMARY_EDGE_BITS + 1);
+        if let Some(ref options) = self.workers.miner_options {
+            if let Some(ref allowed) = options.edge_bits {
+                edge_bits.extend(allowed.iter().cloned());
+            }
+        }
+        edge_bits.sort();
+        edge_bits.dedup();
+        let missing: Vec<String> = edge_bits
+            .iter()
+            .map(|edge_bits| edge_bits.to_string())
+            .filter(|edge_bits| !self.producer.topics.contains_key(edge_bits))
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "No producer topic for edge_bits {}",
+                missing.join(", ")
+            ))
+        }
+    }
+}
+
 pub fn read_config() -> Config {
     let mut config_file = File::open(CONFIG_FILE_NAME).expect("Config file not found");
     let mut toml_str = String::new();
     config_file
         .read_to_string(&mut toml_str)
         .expect("Failure while reading config file");
-    let config: Config = toml::from_str(&toml_str).unwrap();
+    let mut config: Config = toml::from_str(&toml_str).unwrap();
+    config.grin_node.password = config
+        .grin_node
+        .load_password()
+        .expect("Failed to read upstream password");
+    for node in config.grin_nodes.iter_mut() {
+        node.password = node
+            .load_password()
+            .expect("Failed to read upstream password");
+    }
+    config
+        .check_share_topics()
+        .expect("Invalid producer topics");
     return config;
 }
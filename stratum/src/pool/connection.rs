@@ -0,0 +1,273 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Worker and Upstream Connections
+//!
+//! A nonblocking socket, either plaintext or wrapped in a TLS session.
+//! Both behave the same to the protocol: reads and writes return
+//! WouldBlock until the socket is ready again.
+//!
+
+use mio::net::TcpStream;
+use rustc_serialize::hex::FromHex;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, ClientSession, NoClientAuth, PrivateKey, RootCertStore,
+    ServerCertVerified, ServerCertVerifier, ServerConfig, ServerSession, Session, TLSError,
+};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::time::SystemTime;
+use webpki;
+use webpki_roots;
+
+use pool::config::NodeConfig;
+
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(TcpStream, Box<dyn Session>),
+}
+
+impl Connection {
+    pub fn plain(stream: TcpStream) -> Connection {
+        Connection::Plain(stream)
+    }
+
+    pub fn tls(stream: TcpStream, session: Box<dyn Session>) -> Connection {
+        Connection::Tls(stream, session)
+    }
+
+    /// The underlying socket
+    pub fn socket(&self) -> &TcpStream {
+        match *self {
+            Connection::Plain(ref stream) => stream,
+            Connection::Tls(ref stream, _) => stream,
+        }
+    }
+
+    // Write pending TLS records to the socket
+    fn write_tls(stream: &mut TcpStream, session: &mut Box<dyn Session>) -> io::Result<()> {
+        while session.wants_write() {
+            match session.write_tls(stream) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Plain(ref mut stream) => stream.read(buf),
+            Connection::Tls(ref mut stream, ref mut session) => loop {
+                // Return plaintext we have already decrypted
+                let n = session.read(buf)?;
+                if n > 0 {
+                    return Ok(n);
+                }
+                // Read more TLS records, WouldBlock is passed to the caller
+                if session.read_tls(stream)? == 0 {
+                    return Ok(0);
+                }
+                session
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+                // Handshake messages
+                Connection::write_tls(stream, session)?;
+            },
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Plain(ref mut stream) => stream.write(buf),
+            Connection::Tls(ref mut stream, ref mut session) => {
+                // Keep the backpressure in the callers queue, not the session
+                Connection::write_tls(stream, session)?;
+                if session.wants_write() {
+                    return Err(io::Error::new(ErrorKind::WouldBlock, "TLS records pending"));
+                }
+                let n = session.write(buf)?;
+                Connection::write_tls(stream, session)?;
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Plain(ref mut stream) => stream.flush(),
+            Connection::Tls(ref mut stream, ref mut session) => {
+                Connection::write_tls(stream, session)
+            }
+        }
+    }
+}
+
+// ----------------------------------------
+// TLS certificate for worker listen ports
+
+pub struct TlsCertificate {
+    cert_path: String,
+    key_path: String,
+    modified: Option<SystemTime>,
+    config: Arc<ServerConfig>,
+}
+
+impl TlsCertificate {
+    /// Load the certificate chain and private key
+    pub fn load(cert_path: &str, key_path: &str) -> Result<TlsCertificate, String> {
+        Ok(TlsCertificate {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            modified: last_modified(cert_path, key_path),
+            config: server_config(cert_path, key_path)?,
+        })
+    }
+
+    /// Reload the certificate if its files changed, returns true if it was reloaded
+    /// Existing sessions keep the configuration they started with
+    pub fn reload(&mut self) -> Result<bool, String> {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+        if modified == self.modified {
+            return Ok(false);
+        }
+        self.modified = modified;
+        self.config = server_config(&self.cert_path, &self.key_path)?;
+        Ok(true)
+    }
+
+    /// Wrap a newly accepted worker socket
+    pub fn accept(&self, stream: TcpStream) -> Connection {
+        Connection::tls(stream, Box::new(ServerSession::new(&self.config)))
+    }
+}
+
+// ----------------------------------------
+// TLS for the upstream grin node connection
+
+/// Wrap a socket connected to an upstream node in a TLS session
+pub fn connect_tls(stream: TcpStream, node: &NodeConfig) -> Result<Connection, String> {
+    let server_name = match node.tls_server_name {
+        Some(ref name) => name.clone(),
+        None => node.address.clone(),
+    };
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str(&server_name)
+        .map_err(|_| format!("{} - Invalid TLS server name", server_name))?;
+    let config = client_config(node)?;
+    let session = ClientSession::new(&config, dns_name);
+    Ok(Connection::tls(stream, Box::new(session)))
+}
+
+// Trust a custom CA bundle, or the public roots, or only a pinned certificate
+fn client_config(node: &NodeConfig) -> Result<Arc<ClientConfig>, String> {
+    let mut config = ClientConfig::new();
+    match node.tls_ca_file {
+        Some(ref path) => {
+            let file = fs::File::open(path).map_err(|e| format!("{} - {}", path, e))?;
+            match config.root_store.add_pem_file(&mut BufReader::new(file)) {
+                Ok((valid, _)) if valid > 0 => {}
+                _ => return Err(format!("{} - No valid CA certificates found", path)),
+            }
+        }
+        None => {
+            config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        }
+    }
+    if let Some(ref pin) = node.tls_pin {
+        let fingerprint = pin
+            .replace(":", "")
+            .from_hex()
+            .map_err(|_| format!("{} - Invalid certificate fingerprint", pin))?;
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedCertificate {
+                fingerprint: fingerprint,
+            }));
+    }
+    Ok(Arc::new(config))
+}
+
+// Accepts only the certificate with the given SHA-256 fingerprint
+struct PinnedCertificate {
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        match presented_certs.first() {
+            Some(cert) if Sha256::digest(&cert.0).as_slice() == &self.fingerprint[..] => {
+                Ok(ServerCertVerified::assertion())
+            }
+            _ => Err(TLSError::General(
+                "Certificate does not match the pinned fingerprint".to_string(),
+            )),
+        }
+    }
+}
+
+// Most recent modification time of the certificate and key files
+fn last_modified(cert_path: &str, key_path: &str) -> Option<SystemTime> {
+    let cert = fs::metadata(cert_path).and_then(|m| m.modified()).ok();
+    let key = fs::metadata(key_path).and_then(|m| m.modified()).ok();
+    ::std::cmp::max(cert, key)
+}
+
+fn server_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>, String> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|e| format!("{} - Invalid certificate: {:?}", cert_path, e))?;
+    Ok(Arc::new(config))
+}
+
+/// Read a PEM certificate file
+pub fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("{} - {}", path, e))?;
+    match pemfile::certs(&mut BufReader::new(file)) {
+        Ok(ref certs) if certs.is_empty() => Err(format!("{} - No certificates found", path)),
+        Ok(certs) => Ok(certs),
+        Err(_) => Err(format!("{} - Invalid certificate file", path)),
+    }
+}
+
+// Read a PEM private key file, PKCS8 or RSA
+fn load_private_key(path: &str) -> Result<PrivateKey, String> {
+    let file = fs::File::open(path).map_err(|e| format!("{} - {}", path, e))?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(file)).unwrap_or_default();
+    if keys.is_empty() {
+        let file = fs::File::open(path).map_err(|e| format!("{} - {}", path, e))?;
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(file)).unwrap_or_default();
+    }
+    match keys.into_iter().next() {
+        Some(key) => Ok(key),
+        None => Err(format!("{} - No private key found", path)),
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal HTTP Client
+//!
+//! Blocking requests to the pool API, only for use off the event loop
+//!
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// GET a url and return the response body
+pub fn get(url: &str, timeout: Duration) -> Result<String, String> {
+    match request("GET", url, None, timeout)? {
+        (200, body) => Ok(body),
+        (status, _) => Err(format!("Request failed with status {}", status)),
+    }
+}
+
+/// POST a JSON body to a url, returns the response status and body
+pub fn post_json(url: &str, body: &str, timeout: Duration) -> Result<(u16, String), String> {
+    request("POST", url, Some(body), timeout)
+}
+
+fn request(
+    method: &str,
+    url: &str,
+    body: Option<&str>,
+    timeout: Duration,
+) -> Result<(u16, String), String> {
+    let (host, port, path) = parse_url(url)?;
+    let addr = match (host.as_str(), port).to_socket_addrs() {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => addr,
+            None => return Err(format!("{} - Unable to resolve address", host)),
+        },
+        Err(e) => return Err(format!("{} - {}", host, e)),
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| e.to_string())?;
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n",
+        method, path, host
+    );
+    match body {
+        Some(body) => {
+            request += &format!(
+                "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+        None => request += "\r\n",
+    }
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .map_err(|e| e.to_string())?;
+    parse_response(&String::from_utf8_lossy(&response))
+}
+
+// Split a http:// url into host, port and path
+fn parse_url(url: &str) -> Result<(String, u16, String), String> {
+    if !url.starts_with("http://") {
+        return Err(format!("{} - Only http:// urls are supported", url));
+    }
+    let rest = &url["http://".len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(idx) if !authority.ends_with(']') => {
+            let port = authority[idx + 1..]
+                .parse::<u16>()
+                .map_err(|_| format!("{} - Invalid port", url))?;
+            (&authority[..idx], port)
+        }
+        _ => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(format!("{} - Missing host", url));
+    }
+    Ok((
+        host.trim_matches(|c| c == '[' || c == ']').to_string(),
+        port,
+        path.to_string(),
+    ))
+}
+
+// Split a response into its status code and body
+fn parse_response(response: &str) -> Result<(u16, String), String> {
+    let status_line = response.lines().next().unwrap_or("");
+    let status = match status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+    {
+        Some(status) => status,
+        None => return Err(format!("Invalid response: {}", status_line)),
+    };
+    match response.find("\r\n\r\n") {
+        Some(idx) => Ok((status, response[idx + 4..].to_string())),
+        None => Ok((status, String::new())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("http://pool-api:13423/pool/bans"),
+            Ok(("pool-api".to_string(), 13423, "/pool/bans".to_string()))
+        );
+        assert_eq!(
+            parse_url("http://127.0.0.1"),
+            Ok(("127.0.0.1".to_string(), 80, "/".to_string()))
+        );
+        assert_eq!(
+            parse_url("http://[::1]:8080/x?y=1"),
+            Ok(("::1".to_string(), 8080, "/x?y=1".to_string()))
+        );
+        assert!(parse_url("https://pool-api/bans").is_err());
+        assert!(parse_url("http://pool-api:x/bans").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"),
+            Ok((200, "ok".to_string()))
+        );
+        assert_eq!(
+            parse_response("HTTP/1.0 404 NOT FOUND\r\n\r\n"),
+            Ok((404, String::new()))
+        );
+        assert!(parse_response("").is_err());
+        assert!(parse_response("garbage").is_err());
+    }
+}
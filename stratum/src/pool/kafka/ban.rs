@@ -0,0 +1,33 @@
+/// A worker address or login banned automatically for abuse
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BanEvent {
+    pub subject: String, // IP address or login
+    pub kind: String,    // "ip" or "login"
+    pub reason: String,
+    pub duration: u64, // seconds
+    pub count: u32,    // number of times this subject has been banned
+    pub server_id: String,
+    pub timestamp: u32,
+}
+
+impl BanEvent {
+    pub fn new(
+        subject: String,
+        kind: String,
+        reason: String,
+        duration: u64,
+        count: u32,
+        server_id: String,
+        timestamp: u32,
+    ) -> BanEvent {
+        BanEvent {
+            subject,
+            kind,
+            reason,
+            duration,
+            count,
+            server_id,
+            timestamp,
+        }
+    }
+}
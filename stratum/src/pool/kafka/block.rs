@@ -0,0 +1,39 @@
+/// A block solved by one of our workers
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BlockFound {
+    pub height: u64,
+    pub hash: String,
+    pub edge_bits: u32,
+    pub job_id: u64,
+    pub server_id: String,
+    pub worker_id: usize,
+    pub worker_addr: String,
+    pub fullname: String,
+    pub timestamp: u32,
+}
+
+impl BlockFound {
+    pub fn new(
+        height: u64,
+        hash: String,
+        edge_bits: u32,
+        job_id: u64,
+        server_id: String,
+        worker_id: usize,
+        worker_addr: String,
+        fullname: String,
+        timestamp: u32,
+    ) -> BlockFound {
+        BlockFound {
+            height,
+            hash,
+            edge_bits,
+            job_id,
+            server_id,
+            worker_id,
+            worker_addr,
+            fullname,
+            timestamp,
+        }
+    }
+}
@@ -1,7 +1,11 @@
+pub mod ban;
+pub mod block;
 pub mod producer;
 pub mod serialize;
 pub mod share;
 
+pub use self::ban::BanEvent;
+pub use self::block::BlockFound;
 pub use self::producer::{GrinProducer, KafkaProducer};
 pub use self::serialize::LargeArray;
-pub use self::share::{Share, SubmitResult};
+pub use self::share::{Share, SubmitResult, FULLNAME_LIMIT};
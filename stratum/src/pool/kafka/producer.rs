@@ -1,30 +1,35 @@
-use bincode::{deserialize, serialize};
+use bincode::serialize;
+use serde::Serialize;
 use std::collections::HashMap;
-use std::io;
 use std::str::FromStr;
 use std::time::Duration;
 
-use pool::config::{Config, ProducerConfig};
-use pool::logger::LOGGER;
-use pool::proto::SubmitParams;
+use pool::config::ProducerConfig;
 
-use super::share::{Share, SubmitResult};
+use super::ban::BanEvent;
+use super::block::BlockFound;
+use super::share::Share;
 
 use kafka::client::{
     Compression, KafkaClient, RequiredAcks, DEFAULT_CONNECTION_IDLE_TIMEOUT_MILLIS,
 };
 use kafka::producer::{AsBytes, Producer, Record, DEFAULT_ACK_TIMEOUT_MILLIS};
 
+// Kafka topic for block found events
+const BLOCK_TOPIC: &str = "block";
+// Kafka topic for automatic ban events
+const BAN_TOPIC: &str = "ban";
+
 #[derive(Debug)]
-struct ShareWrapper(Vec<u8>);
+struct RecordWrapper(Vec<u8>);
 
-impl ShareWrapper {
-    fn new(share: &Share) -> Self {
-        ShareWrapper(serialize(share).unwrap())
+impl RecordWrapper {
+    fn new<T: Serialize>(value: &T) -> Self {
+        RecordWrapper(serialize(value).unwrap())
     }
 }
 
-impl AsBytes for ShareWrapper {
+impl AsBytes for RecordWrapper {
     fn as_bytes(&self) -> &[u8] {
         &self.0
     }
@@ -33,14 +38,12 @@ impl AsBytes for ShareWrapper {
 pub struct KafkaProducer {
     pub topics: HashMap<String, String>,
     pub client: Producer,
-    pub partitions: i32,
 }
 
 #[derive(Debug, Clone)]
 struct KafkaProducerConfig {
     compression: Compression,
     required_acks: RequiredAcks,
-    batch_size: usize,
     conn_idle_timeout: Duration,
     ack_timeout: Duration,
 }
@@ -56,21 +59,18 @@ impl KafkaProducerConfig {
         KafkaProducerConfig {
             compression: match _compression {
                 None => Compression::NONE,
-                Some(ref s) if s.eq_ignore_ascii_case("none") => Compression::NONE,
-                #[cfg(feature = "gzip")]
-                Some(ref s) if s.eq_ignore_ascii_case("gzip") => Compression::GZIP,
-                #[cfg(feature = "snappy")]
-                Some(ref s) if s.eq_ignore_ascii_case("snappy") => Compression::SNAPPY,
-                Some(s) => panic!(format!("Unsupported compression type: {}", s)),
+                Some(s) if s.eq_ignore_ascii_case("none") => Compression::NONE,
+                Some(s) if s.eq_ignore_ascii_case("gzip") => Compression::GZIP,
+                Some(s) if s.eq_ignore_ascii_case("snappy") => Compression::SNAPPY,
+                Some(s) => panic!("Unsupported compression type: {}", s),
             },
             required_acks: match _required_acks {
                 None => RequiredAcks::One,
-                Some(ref s) if s.eq_ignore_ascii_case("none") => RequiredAcks::None,
-                Some(ref s) if s.eq_ignore_ascii_case("one") => RequiredAcks::One,
-                Some(ref s) if s.eq_ignore_ascii_case("all") => RequiredAcks::All,
-                Some(s) => panic!(format!("Unknown --required-acks argument: {}", s)),
+                Some(s) if s.eq_ignore_ascii_case("none") => RequiredAcks::None,
+                Some(s) if s.eq_ignore_ascii_case("one") => RequiredAcks::One,
+                Some(s) if s.eq_ignore_ascii_case("all") => RequiredAcks::All,
+                Some(s) => panic!("Unknown --required-acks argument: {}", s),
             },
-            batch_size: to_number(_batch_size, 1).unwrap(),
             conn_idle_timeout: Duration::from_millis(
                 to_number(_conn_idle_timeout, DEFAULT_CONNECTION_IDLE_TIMEOUT_MILLIS).unwrap(),
             ),
@@ -107,6 +107,10 @@ pub trait GrinProducer {
     fn from_config(config: &ProducerConfig) -> KafkaProducer;
 
     fn send_data(&mut self, edge_bits: u32, share: Share) -> Result<()>;
+
+    fn send_block(&mut self, block: BlockFound) -> Result<()>;
+
+    fn send_ban(&mut self, ban: BanEvent) -> Result<()>;
 }
 
 impl GrinProducer for KafkaProducer {
@@ -116,20 +120,16 @@ impl GrinProducer for KafkaProducer {
         match client.load_metadata_all() {
             Ok(_) => {
                 let producer = {
-                    let options: Option<HashMap<String, String>> = cfg.options.clone();
-                    let kafka_config: KafkaProducerConfig;
-                    if options.is_some() {
-                        let options = options.unwrap();
-                        kafka_config = KafkaProducerConfig::new(
+                    let kafka_config = match cfg.options {
+                        Some(ref options) => KafkaProducerConfig::new(
                             options.get("compression"),
                             options.get("required_acks"),
                             options.get("batch_size"),
                             options.get("conn_idle_timeout"),
                             options.get("ack_timeout"),
-                        );
-                    } else {
-                        kafka_config = KafkaProducerConfig::default();
-                    }
+                        ),
+                        None => KafkaProducerConfig::default(),
+                    };
                     Producer::from_client(client)
                         .with_ack_timeout(kafka_config.ack_timeout)
                         .with_required_acks(kafka_config.required_acks)
@@ -141,38 +141,70 @@ impl GrinProducer for KafkaProducer {
 
                 KafkaProducer {
                     topics: cfg.topics.clone(),
-                    partitions: cfg.partitions,
                     client: producer,
                 }
             }
-            Err(e) => panic!(format!("{:?}", e)),
+            Err(e) => panic!("{:?}", e),
         }
     }
 
     fn send_data(&mut self, edge_bits: u32, share: Share) -> Result<()> {
-        let topic: Option<&String> = self.topics.get(&edge_bits.to_string());
-        let record = Record::from_value(&topic.unwrap(), ShareWrapper::new(&share));
+        let topic = match self.topics.get(&edge_bits.to_string()) {
+            Some(topic) => topic.clone(),
+            None => bail!("No kafka topic configured for edge_bits {}", edge_bits),
+        };
+        let record = Record::from_value(&topic, RecordWrapper::new(&share));
         self.client.send(&record)?;
         Ok(())
     }
-}
 
-error_chain! {
-    links {
-        Kafka(kafka::error::Error, kafka::error::ErrorKind);
+    fn send_block(&mut self, block: BlockFound) -> Result<()> {
+        let topic = match self.topics.get(BLOCK_TOPIC) {
+            Some(topic) => topic.clone(),
+            None => bail!("No kafka topic configured for found blocks"),
+        };
+        let record = Record::from_value(&topic, RecordWrapper::new(&block));
+        self.client.send(&record)?;
+        Ok(())
     }
-    foreign_links {
-        Io(io::Error);
+
+    fn send_ban(&mut self, ban: BanEvent) -> Result<()> {
+        let topic = match self.topics.get(BAN_TOPIC) {
+            Some(topic) => topic.clone(),
+            None => bail!("No kafka topic configured for ban events"),
+        };
+        let record = Record::from_value(&topic, RecordWrapper::new(&ban));
+        self.client.send(&record)?;
+        Ok(())
+    }
+}
+
+// error_chain 0.10 is pinned by kafka and uses the deprecated Error methods
+#[allow(deprecated)]
+mod errors {
+    use kafka;
+    use std::io;
+
+    error_chain! {
+        links {
+            Kafka(kafka::error::Error, kafka::error::ErrorKind);
+        }
+        foreign_links {
+            Io(io::Error);
+        }
     }
 }
+pub use self::errors::*;
 
 #[cfg(test)]
 mod test {
     use super::*;
     use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
-    use pool::config::{read_config, Config, ProducerConfig};
+    use pool::config::{read_config, ProducerConfig};
+    use pool::kafka::SubmitResult;
 
     #[test]
+    #[ignore] // needs a kafka broker, run with --ignored
     fn test_send_data() {
         let config = read_config();
         let mut kafka_producer = KafkaProducer::from_config(&config.producer);
@@ -186,15 +218,17 @@ mod test {
             SubmitResult::Accept,
             10,
             4,
+            0,
         );
         let result = kafka_producer.send_data(29, share);
-        assert_eq!(result.is_ok(), true, "{}", format!("{:?}", result));
+        assert!(result.is_ok(), "{:?}", result);
     }
 
     #[test]
+    #[ignore] // needs a kafka broker, run with --ignored
     fn test_consumer_data_from_kafka() {
         let config = read_config();
-        let mut kafka_producer = KafkaProducer::from_config(&config.producer);
+        let kafka_producer = KafkaProducer::from_config(&config.producer);
         let share = Share::new(
             9,
             "test_server_id-2".to_owned(),
@@ -205,6 +239,7 @@ mod test {
             SubmitResult::Accept,
             10,
             4,
+            0,
         );
         struct Inner {
             pub producer: KafkaProducer,
@@ -214,10 +249,10 @@ mod test {
             producer: kafka_producer,
         };
         let result = inner.producer.send_data(29, share.clone());
-        assert_eq!(result.is_ok(), true, "{}", format!("{:?}", result));
+        assert!(result.is_ok(), "{:?}", result);
 
         let cfg: &ProducerConfig = &config.producer;
-        let mut consumer = {
+        let _consumer = {
             let mut cb = Consumer::from_hosts(cfg.brokers.clone())
                 .with_group(String::new())
                 .with_fallback_offset(FetchOffset::Earliest)
@@ -227,7 +262,7 @@ mod test {
                 .with_retry_max_bytes_limit(1_000_000)
                 .with_offset_storage(GroupOffsetStorage::Kafka)
                 .with_client_id("kafka-grin-test-consumer".into());
-            cb = cb.with_topic(cfg.topic.clone());
+            cb = cb.with_topic(cfg.topics["29"].clone());
             cb.create().unwrap()
         };
 
@@ -2,7 +2,7 @@ use std::fmt;
 use std::marker::PhantomData;
 
 use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor};
-use serde::ser::{Error, Serialize, SerializeTuple, Serializer};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
 
 pub trait LargeArray<'de>: Sized {
     fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error>;
@@ -28,7 +28,7 @@ macro_rules! makeup_array {
                     {
                         struct ArrayVisitor<T> {
                             e: PhantomData<T>,
-                        };
+                        }
 
                         impl<'de, T> Visitor<'de> for ArrayVisitor<T>
                             where T: Default + Copy + Deserialize<'de> + Serialize
@@ -3,19 +3,40 @@ use std::vec::Vec;
 
 use super::LargeArray;
 
-const FULLNAME_LIMIT: usize = 46;
-const SECONDARY: u32 = 29;
-const PRIMARY: u32 = 31;
+// Room for a wallet address or an email address with a worker name, longer
+// logins are refused by the naming rules
+pub const FULLNAME_LIMIT: usize = 128;
 
+// The values are stored in Share.result, only ever append new variants
 #[repr(i32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SubmitResult {
-    Reject = 0,
-    Accept,
+    Reject = 0,    // Rejected by the upstream node for another reason
+    Accept,        // Accepted by the upstream node
+    Lost,          // Submitted upstream but never got a response
+    Block,         // Accepted and solved a block
+    Duplicate,     // Already submitted
+    Stale,         // For a job that is no longer current
+    LowDifficulty, // Below the worker difficulty
+    Invalid,       // Not a valid solution
+    Syncing,       // The upstream node is syncing
+}
+
+impl SubmitResult {
+    /// Classify an upstream submit error code
+    pub fn from_error_code(code: i32) -> SubmitResult {
+        match code {
+            -32501 => SubmitResult::LowDifficulty,
+            -32502 => SubmitResult::Invalid,
+            -32503 => SubmitResult::Stale,
+            -32701 => SubmitResult::Syncing,
+            _ => SubmitResult::Reject,
+        }
+    }
 }
 
 fn get_inet_addr(worker_addr: &str) -> u32 {
-    let mut addr_port = worker_addr.split(':').collect::<Vec<&str>>();
+    let addr_port = worker_addr.split(':').collect::<Vec<&str>>();
     let (addr, _port) = (addr_port[0], addr_port[1]);
     let addrs = addr
         .split('.')
@@ -31,14 +52,11 @@ fn get_server_id(server_id: &str) -> u16 {
 }
 
 fn get_fullname(fullname: &str) -> [char; FULLNAME_LIMIT] {
-    let mut fullname = fullname.to_string();
-    let length = fullname.len();
-    if length < FULLNAME_LIMIT {
-        fullname.push_str("\0".repeat(FULLNAME_LIMIT - length).as_str())
-    }
-
+    debug_assert!(fullname.chars().count() <= FULLNAME_LIMIT);
     let mut result: [char; FULLNAME_LIMIT] = [char::default(); FULLNAME_LIMIT];
-    result.copy_from_slice(fullname.chars().collect::<Vec<char>>().as_slice());
+    for (slot, c) in result.iter_mut().zip(fullname.chars()) {
+        *slot = c;
+    }
     result
 }
 
@@ -54,7 +72,7 @@ pub struct Share {
     pub blkbits: u32, // 0
     pub result: i32,
     pub height: i32,
-    pub share_diff: u64, // 0
+    pub share_diff: u64,
     pub server_id: u16,
     #[serde(with = "LargeArray")]
     pub fullname: [char; FULLNAME_LIMIT],
@@ -65,23 +83,24 @@ impl Share {
         job_id: u64,
         server_id: String,
         worker_addr: String,
-        worker_id: usize,
+        user_id: i32,
         difficulty: u64,
         fullname: String,
         result: SubmitResult,
         height: i32,
         timestamp: u32,
+        share_diff: u64,
     ) -> Share {
         Share {
             job_id,
             difficulty,
             timestamp,
             height,
+            share_diff,
 
             worker_hash_id: 0,
-            user_id: worker_id as i32,
+            user_id,
             blkbits: 0,
-            share_diff: 0,
 
             result: result as i32,
             server_id: get_server_id(&server_id),
@@ -90,3 +109,47 @@ impl Share {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_submit_result_values() {
+        assert_eq!(SubmitResult::Reject as i32, 0);
+        assert_eq!(SubmitResult::Accept as i32, 1);
+        assert_eq!(SubmitResult::Block as i32, 3);
+        assert_eq!(SubmitResult::Syncing as i32, 8);
+    }
+
+    #[test]
+    fn test_from_error_code() {
+        assert_eq!(
+            SubmitResult::from_error_code(-32501),
+            SubmitResult::LowDifficulty
+        );
+        assert_eq!(SubmitResult::from_error_code(-32502), SubmitResult::Invalid);
+        assert_eq!(SubmitResult::from_error_code(-32503), SubmitResult::Stale);
+        assert_eq!(SubmitResult::from_error_code(-32701), SubmitResult::Syncing);
+        assert_eq!(SubmitResult::from_error_code(-32000), SubmitResult::Reject);
+    }
+
+    #[test]
+    fn test_wallet_address_fullname() {
+        let login = "grin1k60yu2genzhkfxm9dqpmdm9s9z5vqy7eeccpudwsqjf9uhh0tmusywundu.rig1";
+        let share = Share::new(
+            1,
+            "StratumServer-1".to_string(),
+            "10.0.0.1:3333".to_string(),
+            7,
+            1000,
+            login.to_string(),
+            SubmitResult::Accept,
+            100,
+            0,
+            2000,
+        );
+        let fullname: String = share.fullname.iter().take_while(|c| **c != '\0').collect();
+        assert_eq!(fullname, login);
+    }
+}
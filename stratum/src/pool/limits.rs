@@ -0,0 +1,202 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Worker Connection Limits
+//!
+//! Counts open worker connections in total, per source address, per listen
+//! port and per login account, and limits the rate of new connections.
+//!
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+
+use pool::config::WorkerConfig;
+use pool::ratelimit::TokenBucket;
+use pool::worker::Worker;
+
+pub struct ConnectionLimits {
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+    max_per_login: Option<usize>,
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_port: HashMap<u64, usize>,
+    per_login: HashMap<String, usize>, // by account
+    rate: Option<TokenBucket>,         // new connections
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &WorkerConfig) -> ConnectionLimits {
+        ConnectionLimits {
+            max_total: config.max_connections,
+            max_per_ip: config.max_connections_per_ip,
+            max_per_login: config.max_connections_per_login,
+            total: 0,
+            per_ip: HashMap::new(),
+            per_port: HashMap::new(),
+            per_login: HashMap::new(),
+            rate: config.connection_rate.map(|rate| {
+                TokenBucket::new(rate, config.connection_burst.unwrap_or(rate.max(1.0)))
+            }),
+        }
+    }
+
+    /// Count a new connection, or the reason it is refused
+    /// max_port is the limit for the port it arrived on
+    pub fn admit(&mut self, ip: IpAddr, port: u64, max_port: Option<usize>) -> Result<(), String> {
+        if over(self.max_total, self.total) {
+            return Err("Too many connections to the pool".to_string());
+        }
+        if over(max_port, count(&self.per_port, &port)) {
+            return Err("Too many connections to this port".to_string());
+        }
+        if over(self.max_per_ip, count(&self.per_ip, &ip)) {
+            return Err("Too many connections from this address".to_string());
+        }
+        // Checked last so refused connections do not use up the rate
+        if let Some(ref mut rate) = self.rate {
+            if !rate.take() {
+                return Err("Too many new connections, try again later".to_string());
+            }
+        }
+        self.total += 1;
+        *self.per_ip.entry(ip).or_insert(0) += 1;
+        *self.per_port.entry(port).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// A connection counted by admit has closed
+    pub fn release(&mut self, ip: IpAddr, port: u64) {
+        self.total = self.total.saturating_sub(1);
+        decrement(&mut self.per_ip, &ip);
+        decrement(&mut self.per_port, &port);
+    }
+
+    /// A worker has disconnected, release its connection and login
+    pub fn release_worker(&mut self, worker: &Worker) {
+        if let Some(ip) = worker.ip() {
+            self.release(ip, worker.port());
+        }
+        if worker.logged_in() {
+            self.release_login(&worker.account());
+        }
+    }
+
+    /// Count a login to an account, or the reason it is refused
+    pub fn admit_login(&mut self, account: &str) -> Result<(), String> {
+        if over(
+            self.max_per_login,
+            count(&self.per_login, &account.to_string()),
+        ) {
+            return Err("Too many connections for this account".to_string());
+        }
+        *self.per_login.entry(account.to_string()).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// A login counted by admit_login has closed or changed account
+    pub fn release_login(&mut self, account: &str) {
+        decrement(&mut self.per_login, &account.to_string());
+    }
+}
+
+// Is the count already at the limit
+fn over(max: Option<usize>, count: usize) -> bool {
+    match max {
+        Some(max) => count >= max,
+        None => false,
+    }
+}
+
+fn count<K: Hash + Eq>(counts: &HashMap<K, usize>, key: &K) -> usize {
+    *counts.get(key).unwrap_or(&0)
+}
+
+// Decrement a count, and forget keys that reach zero
+fn decrement<K: Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    let remove = match counts.get_mut(key) {
+        Some(count) => {
+            *count = count.saturating_sub(1);
+            *count == 0
+        }
+        None => false,
+    };
+    if remove {
+        counts.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> ConnectionLimits {
+        ConnectionLimits {
+            max_total: Some(4),
+            max_per_ip: Some(2),
+            max_per_login: Some(1),
+            total: 0,
+            per_ip: HashMap::new(),
+            per_port: HashMap::new(),
+            per_login: HashMap::new(),
+            rate: None,
+        }
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let mut limits = limits();
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let c: IpAddr = "10.0.0.3".parse().unwrap();
+        assert!(limits.admit(a, 3333, None).is_ok());
+        assert!(limits.admit(a, 3333, None).is_ok());
+        // Per address
+        assert!(limits.admit(a, 3333, None).is_err());
+        // Per port
+        assert!(limits.admit(b, 3333, Some(2)).is_err());
+        assert!(limits.admit(b, 3334, Some(2)).is_ok());
+        assert!(limits.admit(b, 3334, Some(2)).is_ok());
+        // Total
+        assert!(limits.admit(c, 3335, None).is_err());
+        limits.release(a, 3333);
+        assert!(limits.admit(c, 3335, None).is_ok());
+        assert_eq!(limits.total, 4);
+        assert_eq!(count(&limits.per_ip, &a), 1);
+    }
+
+    #[test]
+    fn test_login_limits() {
+        let mut limits = limits();
+        assert!(limits.admit_login("user").is_ok());
+        // Another worker of the account, such as user.rig2
+        assert!(limits.admit_login("user").is_err());
+        assert!(limits.admit_login("other").is_ok());
+        limits.release_login("user");
+        assert!(!limits.per_login.contains_key("user"));
+        assert!(limits.admit_login("user").is_ok());
+    }
+
+    #[test]
+    fn test_connection_rate() {
+        let mut limits = limits();
+        limits.max_per_ip = None;
+        limits.rate = Some(TokenBucket::new(0.001, 2.0));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(limits.admit(a, 3333, None).is_ok());
+        assert!(limits.admit(a, 3333, None).is_ok());
+        assert!(limits.admit(a, 3333, None).is_err());
+        assert_eq!(limits.total, 2);
+    }
+}
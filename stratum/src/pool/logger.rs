@@ -11,20 +11,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-#[macro_use]
-use lazy_static;
-use slog::{Discard, Drain, Duplicate, Level, LevelFilter, Logger};
+use slog::{Drain, Duplicate, Level, LevelFilter, Logger};
 use slog_async;
 use slog_term;
 use std::fs::OpenOptions;
-use std::{panic, thread};
 
 use pool::config;
 
@@ -41,12 +31,11 @@ lazy_static! {
     let terminal_decorator = slog_term::TermDecorator::new().build();
     let terminal_drain = slog_term::FullFormat::new(terminal_decorator).build().fuse();
     let terminal_drain = LevelFilter::new(terminal_drain, slog_level_stdout).fuse();
-    let mut terminal_drain = slog_async::Async::new(terminal_drain).build().fuse();
+    let terminal_drain = slog_async::Async::new(terminal_drain).build().fuse();
 
     // File drain
     let file = OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
         .truncate(false)
         .open(log_file_path)
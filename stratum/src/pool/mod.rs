@@ -1,7 +1,19 @@
+pub mod auth;
+pub mod ban;
+mod codec;
 pub mod config;
+pub mod connection;
+mod http;
 pub mod kafka;
+pub mod limits;
 pub mod logger;
+pub mod naming;
+pub mod options;
 pub mod pool;
+pub mod pow;
 pub mod proto;
+pub mod ratelimit;
+pub mod reputation;
 pub mod server;
+pub mod vardiff;
 pub mod worker;
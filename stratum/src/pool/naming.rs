@@ -0,0 +1,441 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Worker Login Names
+//!
+//! A miner logs in as "account.worker".  The account ends at the first
+//! separator character and the rest names the rig.  The account may also be
+//! a Grin wallet address or an email address when the operator allows them;
+//! an email address only ends at a separator other than "." or "-", since
+//! those appear in its domain.  Accepted logins are rewritten as the account
+//! and worker joined by the first separator, and must fit the name field of
+//! the share record.
+//!
+
+use pool::config::NamingConfig;
+use pool::kafka::FULLNAME_LIMIT;
+
+const DEFAULT_SEPARATORS: &'static str = ".";
+const DEFAULT_MIN_ACCOUNT_LENGTH: usize = 1;
+const DEFAULT_MAX_ACCOUNT_LENGTH: usize = 20;
+const DEFAULT_MAX_WORKER_LENGTH: usize = 18;
+const DEFAULT_ACCOUNT_CHARS: &'static str = "a-zA-Z0-9_";
+const DEFAULT_WORKER_CHARS: &'static str = "a-zA-Z0-9_.-";
+const DEFAULT_WORKER: &'static str = "__default__";
+
+// Slatepack addresses are bech32, the data part is a 32 byte key and a checksum
+const BECH32_CHARSET: &'static str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const WALLET_ADDRESS_PREFIXES: [&'static str; 2] = ["grin", "tgrin"];
+const WALLET_ADDRESS_DATA_LENGTH: usize = 58;
+
+const MAX_EMAIL_LENGTH: usize = 254;
+
+/// A login split into its account and worker names
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerName {
+    pub account: String,
+    pub worker: String,
+    pub login: String, // account and worker, as the pool records them
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Case {
+    Preserve,
+    Lower,
+    Upper,
+}
+
+// Characters allowed in a name, from a spec such as "a-zA-Z0-9_-"
+#[derive(Debug, Clone)]
+struct CharClass {
+    ranges: Vec<(char, char)>,
+}
+
+impl CharClass {
+    fn parse(spec: &str) -> Result<CharClass, String> {
+        let chars: Vec<char> = spec.chars().collect();
+        let mut ranges = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            // A "-" at either end is itself allowed
+            if i + 2 < chars.len() && chars[i + 1] == '-' {
+                if chars[i] > chars[i + 2] {
+                    return Err(format!(
+                        "{} - Invalid character range {}-{}",
+                        spec,
+                        chars[i],
+                        chars[i + 2]
+                    ));
+                }
+                ranges.push((chars[i], chars[i + 2]));
+                i += 3;
+            } else {
+                ranges.push((chars[i], chars[i]));
+                i += 1;
+            }
+        }
+        Ok(CharClass { ranges: ranges })
+    }
+
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(low, high)| low <= c && c <= high)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NamingRules {
+    separators: Vec<char>,
+    min_account_length: usize,
+    max_account_length: usize,
+    max_worker_length: usize,
+    account_chars: CharClass,
+    worker_chars: CharClass,
+    case: Case,
+    default_worker: Option<String>, // None if a worker name is required
+    wallet_addresses: bool,
+    email_logins: bool,
+}
+
+impl NamingRules {
+    pub fn new(config: Option<NamingConfig>) -> Result<NamingRules, String> {
+        let config = config.unwrap_or(NamingConfig {
+            separators: None,
+            min_account_length: None,
+            max_account_length: None,
+            max_worker_length: None,
+            account_chars: None,
+            worker_chars: None,
+            case: None,
+            default_worker: None,
+            wallet_addresses: None,
+            email_logins: None,
+        });
+        let separators: Vec<char> = config
+            .separators
+            .unwrap_or(DEFAULT_SEPARATORS.to_string())
+            .chars()
+            .collect();
+        if separators.is_empty() {
+            return Err("At least one separator is needed".to_string());
+        }
+        let case = match config.case.as_deref() {
+            None | Some("preserve") => Case::Preserve,
+            Some("lower") => Case::Lower,
+            Some("upper") => Case::Upper,
+            Some(case) => {
+                return Err(format!(
+                    "{} - Invalid case, use lower, upper or preserve",
+                    case
+                ))
+            }
+        };
+        let default_worker = match config.default_worker {
+            Some(ref worker) if worker.is_empty() => None,
+            Some(worker) => Some(worker),
+            None => Some(DEFAULT_WORKER.to_string()),
+        };
+        Ok(NamingRules {
+            separators: separators,
+            min_account_length: config
+                .min_account_length
+                .unwrap_or(DEFAULT_MIN_ACCOUNT_LENGTH),
+            max_account_length: config
+                .max_account_length
+                .unwrap_or(DEFAULT_MAX_ACCOUNT_LENGTH),
+            max_worker_length: config
+                .max_worker_length
+                .unwrap_or(DEFAULT_MAX_WORKER_LENGTH),
+            account_chars: CharClass::parse(
+                &config
+                    .account_chars
+                    .unwrap_or(DEFAULT_ACCOUNT_CHARS.to_string()),
+            )?,
+            worker_chars: CharClass::parse(
+                &config
+                    .worker_chars
+                    .unwrap_or(DEFAULT_WORKER_CHARS.to_string()),
+            )?,
+            case: case,
+            default_worker: default_worker,
+            wallet_addresses: config.wallet_addresses.unwrap_or(false),
+            email_logins: config.email_logins.unwrap_or(false),
+        })
+    }
+
+    /// Check a login, returns its account and worker names or why it is refused
+    pub fn parse(&self, login: &str) -> Result<WorkerName, String> {
+        let (account, worker) = self.split(login);
+        let account = self.check_account(account)?;
+        let worker = match worker {
+            Some(worker) if !worker.is_empty() => self.check_worker(worker)?,
+            _ => match self.default_worker {
+                Some(ref worker) => worker.clone(),
+                None => return Err("Missing worker name".to_string()),
+            },
+        };
+        let login = format!("{}{}{}", account, self.separators[0], worker);
+        if login.chars().count() > FULLNAME_LIMIT {
+            return Err(format!(
+                "Login may be at most {} characters",
+                FULLNAME_LIMIT
+            ));
+        }
+        Ok(WorkerName {
+            login: login,
+            account: account,
+            worker: worker,
+        })
+    }
+
+    // Split at the first separator, after the domain of an email address
+    fn split<'a>(&self, login: &'a str) -> (&'a str, Option<&'a str>) {
+        let start = match login.find('@') {
+            Some(at) if self.email_logins => at + 1,
+            _ => 0,
+        };
+        let found = login[start..]
+            .char_indices()
+            .find(|&(_, c)| self.separators.contains(&c) && !(start > 0 && (c == '.' || c == '-')));
+        match found {
+            Some((idx, c)) => (
+                &login[..start + idx],
+                Some(&login[start + idx + c.len_utf8()..]),
+            ),
+            None => (login, None),
+        }
+    }
+
+    fn check_account(&self, account: &str) -> Result<String, String> {
+        if self.wallet_addresses && is_wallet_address(account) {
+            return Ok(account.to_lowercase());
+        }
+        if self.email_logins && account.contains('@') {
+            if is_email_address(account) {
+                return Ok(account.to_lowercase());
+            }
+            return Err(format!("{} - Invalid email address", account));
+        }
+        let account = self.fold(account);
+        let length = account.chars().count();
+        if length < self.min_account_length || length > self.max_account_length {
+            return Err(format!(
+                "Account name must be {} to {} characters",
+                self.min_account_length, self.max_account_length
+            ));
+        }
+        if let Some(c) = account.chars().find(|c| !self.account_chars.contains(*c)) {
+            return Err(format!("Account name may not contain {:?}", c));
+        }
+        Ok(account)
+    }
+
+    fn check_worker(&self, worker: &str) -> Result<String, String> {
+        let worker = self.fold(worker);
+        if worker.chars().count() > self.max_worker_length {
+            return Err(format!(
+                "Worker name may be at most {} characters",
+                self.max_worker_length
+            ));
+        }
+        if let Some(c) = worker.chars().find(|c| !self.worker_chars.contains(*c)) {
+            return Err(format!("Worker name may not contain {:?}", c));
+        }
+        Ok(worker)
+    }
+
+    fn fold(&self, name: &str) -> String {
+        match self.case {
+            Case::Preserve => name.to_string(),
+            Case::Lower => name.to_lowercase(),
+            Case::Upper => name.to_uppercase(),
+        }
+    }
+}
+
+// A Grin (slatepack) wallet address: bech32 with a valid checksum
+fn is_wallet_address(address: &str) -> bool {
+    // bech32 is all lowercase or all uppercase
+    if address.chars().any(|c| c.is_ascii_lowercase())
+        && address.chars().any(|c| c.is_ascii_uppercase())
+    {
+        return false;
+    }
+    let address = address.to_lowercase();
+    let (prefix, data) = match address.rfind('1') {
+        Some(idx) => (&address[..idx], &address[idx + 1..]),
+        None => return false,
+    };
+    if !WALLET_ADDRESS_PREFIXES.contains(&prefix) || data.len() != WALLET_ADDRESS_DATA_LENGTH {
+        return false;
+    }
+    let mut values: Vec<u8> = prefix.bytes().map(|b| b >> 5).collect();
+    values.push(0);
+    values.extend(prefix.bytes().map(|b| b & 31));
+    for c in data.chars() {
+        match BECH32_CHARSET.find(c) {
+            Some(value) => values.push(value as u8),
+            None => return false,
+        }
+    }
+    bech32_polymod(&values) == 1
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = (checksum & 0x1ffffff) << 5 ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+// A plain user@example.com address, without quoting or comments
+fn is_email_address(address: &str) -> bool {
+    if address.len() > MAX_EMAIL_LENGTH {
+        return false;
+    }
+    let (local, domain) = match address.find('@') {
+        Some(at) => (&address[..at], &address[at + 1..]),
+        None => return false,
+    };
+    let local_ok = !local.is_empty()
+        && local.len() <= 64
+        && local
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._%+-".contains(c));
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    local_ok && domain_ok
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDRESS: &'static str = "grin1k60yu2genzhkfxm9dqpmdm9s9z5vqy7eeccpudwsqjf9uhh0tmusywundu";
+
+    fn config() -> NamingConfig {
+        NamingConfig {
+            separators: None,
+            min_account_length: None,
+            max_account_length: None,
+            max_worker_length: None,
+            account_chars: None,
+            worker_chars: None,
+            case: None,
+            default_worker: None,
+            wallet_addresses: None,
+            email_logins: None,
+        }
+    }
+
+    fn login(rules: &NamingRules, login: &str) -> Result<String, String> {
+        rules.parse(login).map(|name| name.login)
+    }
+
+    #[test]
+    fn test_defaults() {
+        let rules = NamingRules::new(None).unwrap();
+        let name = rules.parse("alice.rig1").unwrap();
+        assert_eq!(name.account, "alice");
+        assert_eq!(name.worker, "rig1");
+        assert_eq!(login(&rules, "alice"), Ok("alice.__default__".to_string()));
+        assert_eq!(login(&rules, "alice."), Ok("alice.__default__".to_string()));
+        assert_eq!(
+            login(&rules, "alice.rack.1"),
+            Ok("alice.rack.1".to_string())
+        );
+        assert!(rules.parse("").is_err());
+        assert!(rules.parse("alice!.rig1").is_err());
+        assert!(rules.parse("alice.rig 1").is_err());
+        assert!(rules.parse("a_very_long_account_name.rig1").is_err());
+        assert!(rules.parse("alice.a_very_long_worker_name").is_err());
+        assert!(rules.parse(ADDRESS).is_err());
+    }
+
+    #[test]
+    fn test_separators_and_case() {
+        let mut config = config();
+        config.separators = Some("._+".to_string());
+        config.case = Some("lower".to_string());
+        config.default_worker = Some("".to_string());
+        config.account_chars = Some("a-z0-9".to_string());
+        let rules = NamingRules::new(Some(config)).unwrap();
+        assert_eq!(login(&rules, "Alice_Rig1"), Ok("alice.rig1".to_string()));
+        assert_eq!(login(&rules, "alice+rig1"), Ok("alice.rig1".to_string()));
+        assert_eq!(rules.parse("alice"), Err("Missing worker name".to_string()));
+    }
+
+    #[test]
+    fn test_wallet_addresses() {
+        let mut config = config();
+        config.wallet_addresses = Some(true);
+        let rules = NamingRules::new(Some(config)).unwrap();
+        let name = rules.parse(&format!("{}.rig1", ADDRESS)).unwrap();
+        assert_eq!(name.account, ADDRESS);
+        assert!(rules.parse(&ADDRESS.to_uppercase()).is_ok());
+        // Bad checksum
+        assert!(rules.parse(&ADDRESS.replace("qjf9", "qjf8")).is_err());
+        assert!(rules.parse("alice.rig1").is_ok());
+    }
+
+    #[test]
+    fn test_email_logins() {
+        let mut config = config();
+        config.email_logins = Some(true);
+        config.separators = Some(".+".to_string());
+        let rules = NamingRules::new(Some(config)).unwrap();
+        let name = rules.parse("Bob.Smith@Mail.example.com+rig1").unwrap();
+        assert_eq!(name.account, "bob.smith@mail.example.com");
+        assert_eq!(name.worker, "rig1");
+        let name = rules.parse("bob@example.com").unwrap();
+        assert_eq!(name.account, "bob@example.com");
+        assert_eq!(name.worker, "__default__");
+        assert!(rules.parse("bob@localhost").is_err());
+        assert!(rules.parse("bob@-example.com").is_err());
+        // Too long for the share record
+        let long = format!("{}@example.com+rig1", "b".repeat(64));
+        assert!(rules.parse(&long).is_ok());
+        let long = format!("{}@{}.example.com+rig1", "b".repeat(64), "m".repeat(63));
+        assert_eq!(
+            rules.parse(&long),
+            Err("Login may be at most 128 characters".to_string())
+        );
+    }
+
+    #[test]
+    fn test_char_class() {
+        let class = CharClass::parse("a-c_-").unwrap();
+        assert!(class.contains('b'));
+        assert!(class.contains('_'));
+        assert!(class.contains('-'));
+        assert!(!class.contains('d'));
+        assert!(CharClass::parse("z-a").is_err());
+        let mut config = config();
+        config.case = Some("title".to_string());
+        assert!(NamingRules::new(Some(config)).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Miner Options
+//!
+//! Many miners can only send a login, a password and an agent, so session
+//! options ride in the password field as key=value items separated by ",",
+//! ";" or spaces:
+//!
+//!     d=4096              fixed difficulty
+//!     mind=1024,maxd=8192 vardiff bounds
+//!     vardiff=off         vardiff on or off
+//!     edge_bits=31        the only graph size the miner submits
+//!
+//! Anything else is the password, rejoined with "," if the field also
+//! held options.
+//!
+
+use pool::config::{MinerOptionsConfig, PortDifficulty};
+use pool::pow::{MAX_PRIMARY_EDGE_BITS, MIN_PRIMARY_EDGE_BITS, SECONDARY_EDGE_BITS};
+
+const DEFAULT_MIN_DIFFICULTY: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MinerOptions {
+    pub difficulty: Option<u64>,
+    pub min_difficulty: Option<u64>,
+    pub max_difficulty: Option<u64>,
+    pub vardiff: Option<bool>,
+    pub edge_bits: Option<u32>,
+    pub password: String, // the password field without the options
+}
+
+impl MinerOptions {
+    /// Split a password field into options and the password, or the reason
+    /// an option is invalid
+    pub fn parse(field: &str) -> Result<MinerOptions, String> {
+        let mut options = MinerOptions::default();
+        let mut password = Vec::new();
+        for item in field
+            .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+            .filter(|item| !item.is_empty())
+        {
+            let (key, value) = match item.find('=') {
+                Some(idx) => (&item[..idx], &item[idx + 1..]),
+                None => {
+                    password.push(item);
+                    continue;
+                }
+            };
+            match key {
+                "d" => options.difficulty = Some(parse_difficulty(item, value)?),
+                "mind" => options.min_difficulty = Some(parse_difficulty(item, value)?),
+                "maxd" => options.max_difficulty = Some(parse_difficulty(item, value)?),
+                "vardiff" => {
+                    options.vardiff = match value {
+                        "on" | "true" | "1" => Some(true),
+                        "off" | "false" | "0" => Some(false),
+                        _ => return Err(format!("{} - Use vardiff=on or vardiff=off", item)),
+                    }
+                }
+                "edge_bits" => match value.parse::<u32>() {
+                    Ok(edge_bits)
+                        if edge_bits == SECONDARY_EDGE_BITS
+                            || (MIN_PRIMARY_EDGE_BITS..=MAX_PRIMARY_EDGE_BITS)
+                                .contains(&edge_bits) =>
+                    {
+                        options.edge_bits = Some(edge_bits)
+                    }
+                    _ => return Err(format!("{} - Unsupported edge_bits", item)),
+                },
+                _ => password.push(item),
+            }
+        }
+        // A password without options is kept exactly as sent
+        options.password = if options.is_empty() {
+            field.to_string()
+        } else {
+            password.join(",")
+        };
+        Ok(options)
+    }
+
+    // Did the field contain any options
+    fn is_empty(&self) -> bool {
+        self.difficulty.is_none()
+            && self.min_difficulty.is_none()
+            && self.max_difficulty.is_none()
+            && self.vardiff.is_none()
+            && self.edge_bits.is_none()
+    }
+
+    /// Keep the options within the limits the operator allows, for the pool
+    /// and for the port the miner connected to.  Difficulties are clamped,
+    /// and a fixed difficulty is only a starting point when miners may not
+    /// turn vardiff off.
+    pub fn bounded(
+        mut self,
+        config: &MinerOptionsConfig,
+        port: &PortDifficulty,
+    ) -> Result<MinerOptions, String> {
+        let min = config
+            .min_difficulty
+            .unwrap_or(DEFAULT_MIN_DIFFICULTY)
+            .max(port.min_difficulty.unwrap_or(port.difficulty));
+        let max = config
+            .max_difficulty
+            .unwrap_or(u64::max_value())
+            .min(port.max_difficulty.unwrap_or(u64::max_value()))
+            .max(min);
+        let clamp = |difficulty: u64| difficulty.max(min).min(max);
+        self.difficulty = self.difficulty.map(&clamp);
+        self.min_difficulty = self.min_difficulty.map(&clamp);
+        self.max_difficulty = self.max_difficulty.map(&clamp);
+        if let (Some(low), Some(high)) = (self.min_difficulty, self.max_difficulty) {
+            if low > high {
+                self.max_difficulty = Some(low);
+            }
+        }
+        // A fixed difficulty turns vardiff off unless the miner asked for both
+        if self.difficulty.is_some() && self.vardiff.is_none() {
+            self.vardiff = Some(false);
+        }
+        if self.vardiff == Some(false) && !config.allow_fixed_difficulty.unwrap_or(true) {
+            self.vardiff = None;
+        }
+        if let Some(edge_bits) = self.edge_bits {
+            if let Some(ref allowed) = config.edge_bits {
+                if !allowed.contains(&edge_bits) {
+                    return Err(format!(
+                        "edge_bits={} is not accepted by this pool",
+                        edge_bits
+                    ));
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+fn parse_difficulty(item: &str, value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(difficulty) if difficulty > 0 => Ok(difficulty),
+        _ => Err(format!("{} - Invalid difficulty", item)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> MinerOptionsConfig {
+        MinerOptionsConfig {
+            min_difficulty: Some(16),
+            max_difficulty: Some(65536),
+            allow_fixed_difficulty: None,
+            edge_bits: Some(vec![29, 31]),
+        }
+    }
+
+    fn port(difficulty: u64) -> PortDifficulty {
+        PortDifficulty {
+            port: 3333,
+            difficulty: difficulty,
+            min_difficulty: None,
+            max_difficulty: None,
+            max_connections: None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let options = MinerOptions::parse("x").unwrap();
+        assert_eq!(options.password, "x");
+        assert_eq!(options.difficulty, None);
+
+        let options = MinerOptions::parse("secret;d=4096, vardiff=on edge_bits=31").unwrap();
+        assert_eq!(options.difficulty, Some(4096));
+        assert_eq!(options.vardiff, Some(true));
+        assert_eq!(options.edge_bits, Some(31));
+        assert_eq!(options.password, "secret");
+
+        let options = MinerOptions::parse("mind=100,maxd=200").unwrap();
+        assert_eq!(options.min_difficulty, Some(100));
+        assert_eq!(options.max_difficulty, Some(200));
+        assert_eq!(options.password, "");
+
+        // Unknown keys belong to the password
+        assert_eq!(MinerOptions::parse("a=b").unwrap().password, "a=b");
+
+        assert!(MinerOptions::parse("d=0").is_err());
+        assert!(MinerOptions::parse("d=lots").is_err());
+        assert!(MinerOptions::parse("vardiff=maybe").is_err());
+        assert!(MinerOptions::parse("edge_bits=30").is_err());
+    }
+
+    #[test]
+    fn test_bounded() {
+        let options = MinerOptions::parse("d=1000000").unwrap();
+        let options = options.bounded(&config(), &port(1)).unwrap();
+        assert_eq!(options.difficulty, Some(65536));
+        assert_eq!(options.vardiff, Some(false));
+
+        let options = MinerOptions::parse("mind=1,maxd=8").unwrap();
+        let options = options.bounded(&config(), &port(1)).unwrap();
+        assert_eq!(options.min_difficulty, Some(16));
+        assert_eq!(options.max_difficulty, Some(16));
+
+        let mut config = config();
+        config.allow_fixed_difficulty = Some(false);
+        let options = MinerOptions::parse("d=64,vardiff=off").unwrap();
+        let options = options.bounded(&config, &port(1)).unwrap();
+        assert_eq!(options.difficulty, Some(64));
+        assert_eq!(options.vardiff, None);
+
+        let options = MinerOptions::parse("edge_bits=32").unwrap();
+        assert!(options.bounded(&config, &port(1)).is_err());
+    }
+
+    #[test]
+    fn test_port_bounds() {
+        // The port difficulty is the floor unless the port sets a lower one
+        let options = MinerOptions::parse("d=1").unwrap();
+        let options = options.bounded(&config(), &port(1000)).unwrap();
+        assert_eq!(options.difficulty, Some(1000));
+
+        let mut port = port(1000);
+        port.min_difficulty = Some(100);
+        port.max_difficulty = Some(4000);
+        let options = MinerOptions::parse("mind=1,maxd=8192").unwrap();
+        let options = options.bounded(&config(), &port).unwrap();
+        assert_eq!(options.min_difficulty, Some(100));
+        assert_eq!(options.max_difficulty, Some(4000));
+    }
+}
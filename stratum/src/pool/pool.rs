@@ -13,68 +13,79 @@
 
 use bufstream::BufStream;
 use chrono::offset::Utc;
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::sync::{Arc, Mutex, RwLock};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::time;
 use std::time::Instant;
-use std::{thread, time};
 
-use pool::config::{Config, NodeConfig, PoolConfig, WorkerConfig};
-use pool::kafka::{GrinProducer, KafkaProducer, Share, SubmitResult};
+use pool::auth::{AuthResult, Authorizer};
+use pool::ban::BanList;
+use pool::config::{Config, PortDifficulty};
+use pool::connection::{Connection, TlsCertificate};
+use pool::kafka::{BanEvent, GrinProducer, KafkaProducer, Share, SubmitResult};
+use pool::limits::ConnectionLimits;
 use pool::logger::LOGGER;
-use pool::proto::{JobTemplate, RpcError, SubmitParams};
+use pool::naming::NamingRules;
+use pool::options::MinerOptions;
+use pool::pow;
+use pool::proto::{error_response, JobTemplate, RpcError, SubmitParams};
+use pool::reputation::{AutoBan, Event, Reputation, Subject};
 use pool::server::Server;
-use pool::worker::Worker;
+use pool::vardiff::VarDiff;
+use pool::worker::{SessionTimeouts, Timeout, Worker, DEFAULT_MAX_STRIKES};
 
 // ----------------------------------------
-// Worker Connection Thread Function
+// Event Loop Tokens
+//
+// One token per upstream server, then one per listen port, then one per worker
 
-// Run in a thread. Adds new connections to the workers list
-fn accept_workers(
-    id: String,
-    address: String,
-    difficulty: u64,
-    workers: &mut Arc<Mutex<Vec<Worker>>>,
-) {
-    let listener = TcpListener::bind(address).expect("Failed to bind to listen address");
-    let mut worker_id: usize = 0;
-    let banned: HashMap<SocketAddr, Instant> = HashMap::new();
-    // XXX TODO: Call the pool-api to get a list of banned IPs, refresh that list sometimes
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                // XXX ALWAYS DO THIS FIRST - Check if this ip is banned and if so, drop it
-                let worker_addr = stream.peer_addr().unwrap();
-                if banned.contains_key(&worker_addr) {
-                    let _ = stream.shutdown(Shutdown::Both);
-                    continue;
-                }
-                warn!(
-                    LOGGER,
-                    "{} - Worker Listener - New connection from {}",
-                    id,
-                    stream.peer_addr().unwrap()
-                );
-                stream
-                    .set_nonblocking(true)
-                    .expect("set_nonblocking call failed");
-                let mut worker =
-                    Worker::new(worker_id, worker_addr.to_string(), BufStream::new(stream));
-                worker.set_difficulty(difficulty);
-                workers.lock().unwrap().push(worker);
-                worker_id = worker_id + 1;
-            }
-            Err(e) => {
-                warn!(
-                    LOGGER,
-                    "{} - Worker Listener - Error accepting connection: {:?}", id, e
-                );
-            }
+const SERVER_TOKEN_BASE: usize = 0;
+const LISTENER_TOKEN_BASE: usize = 256;
+const WORKER_TOKEN_BASE: usize = 1024;
+
+// Seconds without a new job from the upstream server before we fail over
+const DEFAULT_JOB_TIMEOUT: u64 = 180;
+
+// Seconds a worker has to log in, and may stay silent
+const DEFAULT_LOGIN_TIMEOUT: u64 = 60;
+const DEFAULT_IDLE_TIMEOUT: u64 = 600;
+
+// How often (in milliseconds) the event loop wakes up when nothing is happening
+const POLL_INTERVAL_MS: u64 = 1000;
+// How often (in milliseconds) to check for finished login lookups while workers wait
+const AUTH_POLL_INTERVAL_MS: u64 = 20;
+
+// ----------------------------------------
+// A listen port for worker connections
+
+struct Listener {
+    listener: TcpListener,
+    port: PortDifficulty,
+    tls: bool,
+}
+
+// ----------------------------------------
+// Worker sessions closed for missing a deadline
+
+#[derive(Debug, Default)]
+struct TimeoutStats {
+    login: u64,
+    idle: u64,
+    share: u64,
+}
+
+impl TimeoutStats {
+    fn record(&mut self, timeout: Timeout) {
+        match timeout {
+            Timeout::Login => self.login += 1,
+            Timeout::Idle => self.idle += 1,
+            Timeout::Share => self.share += 1,
         }
     }
-    // close the socket server
-    drop(listener);
 }
 
 // ----------------------------------------
@@ -84,8 +95,25 @@ pub struct Pool {
     id: String,
     job: JobTemplate,
     config: Config,
-    server: Server,
-    workers: Arc<Mutex<Vec<Worker>>>,
+    servers: Vec<Server>, // One per upstream node, most preferred first
+    active: usize,        // Index of the server we are mining on
+    kafka: KafkaProducer,
+    poll: Poll,
+    listeners: Vec<Listener>,
+    tls: Option<TlsCertificate>, // Certificate for the TLS listen ports
+    workers: HashMap<usize, Worker>,
+    next_worker_id: usize,
+    next_tick: Instant, // Time of the next periodic housekeeping pass
+    bans: BanList,
+    reputation: Reputation, // automatic bans for misbehaving workers
+    limits: ConnectionLimits,
+    naming: NamingRules,
+    auth: Option<Authorizer>, // login lookups, any login is accepted without one
+    awaiting_auth: HashSet<usize>, // Workers whose login is being looked up
+    timeouts: SessionTimeouts,
+    timeout_stats: TimeoutStats,
+    backlog: HashSet<usize>, // Workers with requests left unread after their last pass
+    jobs: HashMap<u64, JobTemplate>, // job_id, job for the current block
     duplicates: HashMap<Vec<u32>, usize>, // nonce, worker id who first submitted it
 }
 
@@ -96,75 +124,358 @@ impl Pool {
             id: "Grin Pool".to_string(),
             job: JobTemplate::new(),
             config: config.clone(),
-            server: Server::new(config.clone()),
-            workers: Arc::new(Mutex::new(Vec::new())),
+            servers: config
+                .upstream_nodes()
+                .into_iter()
+                .map(|node| Server::new(config.clone(), node))
+                .collect(),
+            active: 0,
+            kafka: KafkaProducer::from_config(&config.producer),
+            poll: Poll::new().expect("Failed to create event loop"),
+            listeners: Vec::new(),
+            tls: None,
+            workers: HashMap::new(),
+            next_worker_id: 0,
+            next_tick: Instant::now(),
+            bans: BanList::new(config.bans.clone()),
+            reputation: Reputation::new(config.reputation.clone()),
+            limits: ConnectionLimits::new(&config.workers),
+            naming: NamingRules::new(config.workers.naming.clone())
+                .expect("Invalid worker naming rules"),
+            auth: config.auth.clone().map(Authorizer::new),
+            awaiting_auth: HashSet::new(),
+            timeouts: SessionTimeouts {
+                login: timeout(config.workers.login_timeout, DEFAULT_LOGIN_TIMEOUT),
+                idle: timeout(config.workers.idle_timeout, DEFAULT_IDLE_TIMEOUT),
+                share: timeout(config.workers.share_timeout, 0),
+            },
+            timeout_stats: TimeoutStats::default(),
+            backlog: HashSet::new(),
+            jobs: HashMap::new(),
             duplicates: HashMap::new(),
         }
     }
 
     /// Run the Pool
     pub fn run(&mut self) {
-        // Start a thread for each listen port to accept new worker connections
-        for port_difficulty in &self.config.workers.port_difficulty {
-            let mut workers_th = self.workers.clone();
-            let id_th = self.id.clone();
-            let address_th = self.config.workers.listen_address.clone()
-                + ":"
-                + &port_difficulty.port.to_string();
-            let difficulty_th = port_difficulty.difficulty;
-            let _listener_th = thread::spawn(move || {
-                accept_workers(id_th, address_th, difficulty_th, &mut workers_th);
-            });
+        // Load the certificate for TLS ports
+        if !self.config.workers.tls_port_difficulty.is_empty() {
+            let cert_path = self
+                .config
+                .workers
+                .tls_certificate
+                .clone()
+                .expect("TLS ports need a tls_certificate");
+            let key_path = self
+                .config
+                .workers
+                .tls_private_key
+                .clone()
+                .expect("TLS ports need a tls_private_key");
+            self.tls = Some(
+                TlsCertificate::load(&cert_path, &key_path)
+                    .expect("Failed to load TLS certificate"),
+            );
+        }
+        // Load the ban file before accepting anyone
+        self.bans.refresh();
+        // Bind each listen port and register it with the event loop
+        for port_difficulty in self.config.workers.port_difficulty.clone() {
+            self.listen(port_difficulty, false);
+        }
+        for port_difficulty in self.config.workers.tls_port_difficulty.clone() {
+            self.listen(port_difficulty, true);
         }
 
         // ------------
         // Main loop
+        let mut events = Events::with_capacity(1024);
         loop {
             // XXX TODO: Error checking
 
-            // (re)connect if server is not connected or is in error state
-            match self.server.connect() {
+            // Wait for something to happen, unless workers have requests waiting
+            let poll_interval = if !self.backlog.is_empty() {
+                0
+            } else if !self.awaiting_auth.is_empty() {
+                AUTH_POLL_INTERVAL_MS
+            } else {
+                POLL_INTERVAL_MS
+            };
+            match self.poll.poll(
+                &mut events,
+                Some(time::Duration::from_millis(poll_interval)),
+            ) {
                 Ok(_) => {}
+                Err(e) => {
+                    error!(LOGGER, "{} - Event loop error: {}", self.id, e);
+                    continue;
+                }
+            }
+
+            // Periodic housekeeping, (re)connect to upstream servers
+            if Instant::now() >= self.next_tick {
+                self.tick();
+                self.next_tick = Instant::now() + time::Duration::from_millis(POLL_INTERVAL_MS);
+            }
+
+            // Workers whose login lookup finished
+            let auth_finished = match self.auth {
+                Some(ref mut auth) => auth.poll(),
+                None => false,
+            };
+            if auth_finished {
+                let awaiting: Vec<usize> = self.awaiting_auth.drain().collect();
+                for worker_id in awaiting {
+                    self.process_worker_event(worker_id);
+                }
+            }
+
+            // Workers that sent more requests than one pass handles
+            let backlog: Vec<usize> = self.backlog.drain().collect();
+            for worker_id in backlog {
+                self.process_worker_event(worker_id);
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    Token(t) if t < LISTENER_TOKEN_BASE => {
+                        self.process_server_event(t - SERVER_TOKEN_BASE);
+                    }
+                    Token(t) if t >= WORKER_TOKEN_BASE => {
+                        self.process_worker_event(t - WORKER_TOKEN_BASE);
+                    }
+                    Token(t) => {
+                        self.accept_workers(t - LISTENER_TOKEN_BASE);
+                    }
+                }
+            }
+        }
+    }
+
+    // ------------
+    // Pool Methods
+    //
+
+    // Bind a listen port and register it with the event loop
+    fn listen(&mut self, port_difficulty: PortDifficulty, tls: bool) {
+        let address =
+            self.config.workers.listen_address.clone() + ":" + &port_difficulty.port.to_string();
+        let address = address
+            .to_socket_addrs()
+            .expect("Failed to resolve listen address")
+            .next()
+            .expect("Failed to resolve listen address");
+        let listener = TcpListener::bind(&address).expect("Failed to bind to listen address");
+        let token = Token(LISTENER_TOKEN_BASE + self.listeners.len());
+        // Level triggered so connections we could not accept yet are reported again
+        self.poll
+            .register(&listener, token, Ready::readable(), PollOpt::level())
+            .expect("Failed to register listener");
+        self.listeners.push(Listener {
+            listener: listener,
+            port: port_difficulty,
+            tls: tls,
+        });
+    }
+
+    // Pick up a renewed TLS certificate, new sessions use it
+    fn reload_certificate(&mut self) {
+        if let Some(ref mut tls) = self.tls {
+            match tls.reload() {
+                Ok(true) => {
+                    warn!(LOGGER, "{} - Reloaded TLS certificate", self.id);
+                }
+                Ok(false) => {}
                 Err(e) => {
                     error!(
                         LOGGER,
-                        "{} - Unable to connect to upstream server: {}", self.id, e
+                        "{} - Unable to reload TLS certificate: {}", self.id, e
                     );
-                    thread::sleep(time::Duration::from_secs(1));
-                    continue;
                 }
             }
+        }
+    }
 
-            // check the server for messages and handle them
-            let _ = self.process_server_messages();
+    // Reload the ban list, and drop connected workers that are now banned
+    fn refresh_bans(&mut self) {
+        if !self.bans.refresh() {
+            return;
+        }
+        for worker in self.workers.values_mut() {
+            let banned = match worker.addr.parse::<SocketAddr>() {
+                Ok(addr) => self.bans.is_banned(&addr.ip()),
+                Err(_) => false,
+            };
+            if banned {
+                warn!(
+                    LOGGER,
+                    "{} - Disconnecting banned worker {} at {}",
+                    self.id,
+                    worker.id(),
+                    worker.addr
+                );
+                worker.set_error();
+            }
+        }
+    }
 
-            // if the server gave us a new block
-            let _ = self.accept_new_job();
+    // Feed the reputation tracker, and enforce a ban if one results
+    fn report(&mut self, subject: Subject, event: Event) {
+        if let Some(ban) = self.reputation.record(subject, event) {
+            self.enforce_ban(ban);
+        }
+    }
 
-            // Process messages from the workers
-            let _ = self.process_worker_messages();
+    // Report an event for both the address and the login of a worker
+    fn report_worker(&mut self, worker: &Worker, event: Event) {
+        if let Some(ip) = worker.ip() {
+            self.report(Subject::Ip(ip), event);
+        }
+        if worker.logged_in() {
+            self.report(Subject::Login(worker.account()), event);
+        }
+    }
 
-            // Process worker shares
-            let _ = self.process_shares();
+    // Is the worker's address or login banned by the reputation tracker
+    fn is_worker_banned(&self, worker: &Worker) -> bool {
+        let ip_banned = match worker.ip() {
+            Some(ip) => self.reputation.is_banned(&Subject::Ip(ip)),
+            None => false,
+        };
+        ip_banned
+            || (worker.logged_in() && self.reputation.is_banned(&Subject::Login(worker.account())))
+    }
 
-            // Send jobs to needy workers
-            let _ = self.send_jobs();
+    // Disconnect the workers a new automatic ban applies to, and record it
+    fn enforce_ban(&mut self, ban: AutoBan) {
+        warn!(
+            LOGGER,
+            "{} - Banning {} {} for {} seconds (ban {}): {}",
+            self.id,
+            ban.subject.kind(),
+            ban.subject,
+            ban.duration.as_secs(),
+            ban.count,
+            ban.reason
+        );
+        for worker in self.workers.values_mut() {
+            let matches = match ban.subject {
+                Subject::Ip(ref ip) => worker.ip() == Some(*ip),
+                Subject::Login(ref account) => worker.logged_in() && worker.account() == *account,
+            };
+            if matches {
+                worker.set_error();
+            }
+        }
+        let event = BanEvent::new(
+            ban.subject.to_string(),
+            ban.subject.kind().to_string(),
+            ban.reason.clone(),
+            ban.duration.as_secs(),
+            ban.count,
+            self.servers[self.active].get_id(),
+            Utc::now().timestamp() as u32,
+        );
+        let _ = self.kafka.send_ban(event);
+    }
 
-            // Delete workers in error state
-            let _num_active_workers = self.clean_workers();
+    // (re)connect to any upstream server that is not connected or is in error state
+    fn connect_servers(&mut self) {
+        for (idx, server) in self.servers.iter_mut().enumerate() {
+            match server.connect(&self.poll, Token(SERVER_TOKEN_BASE + idx)) {
+                Ok(_) => {}
+                Err(e) => {
+                    error!(
+                        LOGGER,
+                        "{} - Unable to connect to upstream server {}: {}",
+                        self.id,
+                        server.address(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    // An upstream server has sent us something, or finished connecting
+    fn process_server_event(&mut self, server_idx: usize) {
+        if server_idx >= self.servers.len() {
+            return;
+        }
+        if self.servers[server_idx].is_connecting() {
+            match self.servers[server_idx].finish_connect() {
+                Ok(_) => {}
+                Err(e) => {
+                    error!(
+                        LOGGER,
+                        "{} - Unable to connect to upstream server {}: {}",
+                        self.id,
+                        self.servers[server_idx].address(),
+                        e
+                    );
+                    return;
+                }
+            }
+        }
+        // Write anything still queued for the server
+        let _ = self.servers[server_idx].flush();
+        // check the server for messages and handle them
+        let _ = self.process_server_messages(server_idx);
+        // fail over or back if this server changed health
+        self.select_server();
+        // if the server gave us a new block
+        self.accept_new_job();
+    }
 
-            thread::sleep(time::Duration::from_millis(50));
+    // Check the health of each upstream server, unhealthy ones are reconnected
+    fn check_servers(&mut self) {
+        for server in self.servers.iter_mut() {
+            server.expire_submits(&mut self.workers, &mut self.kafka);
+            match server.check_health() {
+                Ok(_) => {}
+                Err(e) => {
+                    error!(
+                        LOGGER,
+                        "{} - Upstream server is unhealthy, reconnecting: {}", self.id, e
+                    );
+                }
+            }
         }
     }
 
-    // ------------
-    // Pool Methods
-    //
+    // Mine on the most preferred healthy upstream server
+    fn select_server(&mut self) {
+        let job_timeout = time::Duration::from_secs(
+            self.config
+                .server
+                .job_timeout
+                .unwrap_or(DEFAULT_JOB_TIMEOUT),
+        );
+        let best = self
+            .servers
+            .iter()
+            .position(|server| server.is_healthy(job_timeout));
+        match best {
+            Some(idx) if idx != self.active => {
+                warn!(
+                    LOGGER,
+                    "{} - Switching upstream server from {} to {}",
+                    self.id,
+                    self.servers[self.active].address(),
+                    self.servers[idx].address()
+                );
+                self.active = idx;
+                // Job ids are only meaningful to the server that issued them
+                self.jobs = HashMap::new();
+            }
+            _ => {}
+        }
+    }
 
-    // Process messages from the upstream server
+    // Process messages from an upstream server
     // Will contain job requests, submit results, status results, etc...
-    fn process_server_messages(&mut self) -> Result<(), RpcError> {
-        match self.server.process_messages(&mut self.workers) {
+    fn process_server_messages(&mut self, server_idx: usize) -> Result<(), RpcError> {
+        match self.servers[server_idx].process_messages(&mut self.workers, &mut self.kafka) {
             Ok(_) => {
                 return Ok(());
             }
@@ -177,43 +488,301 @@ impl Pool {
                 // There are also special case(s) where we want to do something for a specific
                 // error
                 if e.message.contains("Node is syncing") {
-                    thread::sleep(time::Duration::from_secs(2));
+                    self.servers[server_idx].schedule_reconnect(time::Duration::from_secs(2));
                 }
                 return Err(e);
             }
         }
     }
 
-    fn process_worker_messages(&mut self) {
-        let mut workers_l = self.workers.lock().unwrap();
-        for worker in workers_l.iter_mut() {
-            let result = worker.process_messages();
-            match result {
-                Err(ref s) if s == "invalid worker name" => {
-                    warn!(LOGGER, "Remove worker id: {}", worker.id);
-                    worker.set_error();
+    // Accept all pending connections on a listen port
+    fn accept_workers(&mut self, listener_idx: usize) {
+        loop {
+            let (stream, worker_addr) = match self.listeners[listener_idx].listener.accept() {
+                Ok(conn) => conn,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        LOGGER,
+                        "{} - Worker Listener - Error accepting connection: {:?}", self.id, e
+                    );
+                    return;
+                }
+            };
+            // ALWAYS DO THIS FIRST - Check if this ip is banned and if so, drop it
+            if self.bans.is_banned(&worker_addr.ip()) {
+                debug!(
+                    LOGGER,
+                    "{} - Worker Listener - Dropping banned connection from {}",
+                    self.id,
+                    worker_addr
+                );
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
+            }
+            // Reconnect storms count against the address
+            let subject = Subject::Ip(worker_addr.ip());
+            self.report(subject.clone(), Event::Connect);
+            if self.reputation.is_banned(&subject) {
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
+            }
+            let port = self.listeners[listener_idx].port.clone();
+            match self
+                .limits
+                .admit(worker_addr.ip(), port.port, port.max_connections)
+            {
+                Ok(_) => {}
+                Err(reason) => {
+                    debug!(
+                        LOGGER,
+                        "{} - Worker Listener - Refusing connection from {}: {}",
+                        self.id,
+                        worker_addr,
+                        reason
+                    );
+                    // Tell a plaintext client why, a TLS client has not finished its handshake
+                    if !self.listeners[listener_idx].tls {
+                        refuse(&stream, reason);
+                    }
+                    let _ = stream.shutdown(Shutdown::Both);
+                    continue;
                 }
+            }
+            warn!(
+                LOGGER,
+                "{} - Worker Listener - New connection from {}", self.id, worker_addr
+            );
+            let worker_id = self.next_worker_id;
+            self.next_worker_id += 1;
+            match self.poll.register(
+                &stream,
+                Token(WORKER_TOKEN_BASE + worker_id),
+                Ready::readable() | Ready::writable(),
+                PollOpt::edge(),
+            ) {
                 Ok(_) => {}
-                Err(_) => {}
+                Err(e) => {
+                    warn!(
+                        LOGGER,
+                        "{} - Worker Listener - Error registering connection: {:?}", self.id, e
+                    );
+                    self.limits.release(worker_addr.ip(), port.port);
+                    continue;
+                }
+            }
+            let connection = match self.tls {
+                Some(ref tls) if self.listeners[listener_idx].tls => tls.accept(stream),
+                _ => Connection::plain(stream),
+            };
+            let mut worker = Worker::new(
+                worker_id,
+                worker_addr.to_string(),
+                BufStream::new(connection),
+            );
+            worker.set_port(port.port);
+            worker.set_difficulty(port.difficulty);
+            worker.set_max_strikes(
+                self.config
+                    .workers
+                    .max_strikes
+                    .unwrap_or(DEFAULT_MAX_STRIKES),
+            );
+            if let Some(max_message_size) = self.config.workers.max_message_size {
+                worker.set_max_message_size(max_message_size);
+            }
+            if let Some(max_queued_messages) = self.config.workers.max_queued_messages {
+                worker.set_max_queued_messages(max_queued_messages);
+            }
+            if let Some(max_messages) = self.config.workers.max_messages_per_pass {
+                worker.set_max_messages_per_pass(max_messages);
+            }
+            if let Some(ref rate_limits) = self.config.workers.rate_limits {
+                worker.set_rate_limits(rate_limits);
+            }
+            if let Some(ref vardiff_config) = self.config.workers.vardiff {
+                let vardiff = VarDiff::new(
+                    vardiff_config.clone(),
+                    port.min_difficulty.unwrap_or(port.difficulty),
+                    port.max_difficulty.unwrap_or(u64::max_value()),
+                );
+                worker.set_vardiff(vardiff);
             }
+            self.workers.insert(worker_id, worker);
         }
     }
 
-    fn send_jobs(&mut self) {
-        let mut workers_l = self.workers.lock().unwrap();
-        for worker in workers_l.iter_mut() {
-            if worker.needs_job {
-                // Randomize the nonce
-                // XXX TODO (Need to know block header format and deserialize it
-                worker.send_job(&mut self.server.job.clone());
+    // A worker has sent us something - process its messages and shares,
+    // send it a job if it needs one, and drop it if it is in error state
+    fn process_worker_event(&mut self, worker_id: usize) {
+        let mut worker = match self.workers.remove(&worker_id) {
+            Some(worker) => worker,
+            None => {
+                return;
             }
+        };
+        // Write anything still queued for the worker
+        let _ = worker.flush();
+        worker.start_pass();
+        // Process messages from the worker
+        loop {
+            let _ = worker.process_messages();
+            // Accept or reject a login, then read on
+            if worker.error() || worker.login_request().is_none() {
+                break;
+            }
+            self.check_login(&mut worker);
+            // Rejected, or still waiting for the login lookup
+            if worker.error() || worker.login_request().is_some() {
+                break;
+            }
+        }
+        for event in worker.take_events() {
+            self.report_worker(&worker, event);
+        }
+        // Process worker shares
+        self.process_shares(&mut worker);
+        // Send the new difficulty with a fresh job if the worker was retargeted
+        if worker.retarget_difficulty() {
+            worker.needs_job = true;
+        }
+        // Send a job if the worker needs one
+        if worker.needs_job {
+            // Randomize the nonce
+            // XXX TODO (Need to know block header format and deserialize it
+            let _ = worker.send_job(&mut self.job.clone());
+        }
+        // Drop workers that were banned while they were out of the map
+        if self.is_worker_banned(&worker) {
+            worker.set_error();
+        }
+        // Delete workers in error state
+        if worker.error() {
+            warn!(LOGGER, "{} - Dropping worker: {}", self.id, worker.id());
+            self.limits.release_worker(&worker);
+            return;
         }
+        if worker.has_unread() {
+            self.backlog.insert(worker_id);
+        }
+        self.workers.insert(worker_id, worker);
+    }
+
+    // Accept a workers login unless its name or options are invalid, it is
+    // banned, refused by the pool API or over its connection limit.  The login
+    // stays pending while it is looked up.
+    fn check_login(&mut self, worker: &mut Worker) {
+        let login_params = match worker.login_request() {
+            Some(login_params) => login_params.clone(),
+            None => return,
+        };
+        let name = match self.naming.parse(&login_params.login) {
+            Ok(name) => name,
+            Err(reason) => {
+                worker.refuse_login(RpcError {
+                    code: -32602,
+                    message: format!("Invalid login: {}", reason),
+                });
+                return;
+            }
+        };
+        // Options in the password field, within the limits the operator allows
+        let port = self
+            .listeners
+            .iter()
+            .map(|listener| &listener.port)
+            .find(|port| port.port == worker.port());
+        let options = match (&self.config.workers.miner_options, port) {
+            (Some(config), Some(port)) => MinerOptions::parse(&login_params.pass)
+                .and_then(|options| options.bounded(config, port)),
+            (Some(_), None) => Err("Unknown port".to_string()),
+            (None, _) => Ok(MinerOptions {
+                password: login_params.pass.clone(),
+                ..MinerOptions::default()
+            }),
+        };
+        let options = match options {
+            Ok(options) => options,
+            Err(reason) => {
+                worker.refuse_login(RpcError {
+                    code: -32602,
+                    message: format!("Invalid password options: {}", reason),
+                });
+                return;
+            }
+        };
+        if self
+            .reputation
+            .is_banned(&Subject::Login(name.account.clone()))
+        {
+            worker.reject_login(RpcError {
+                code: -32000,
+                message: "Login is temporarily banned".to_string(),
+            });
+            return;
+        }
+        let user_id = match self.auth {
+            Some(ref mut auth) => match auth.authorize(&name.account, &options.password) {
+                Some(AuthResult::Ok(user_id)) => user_id,
+                Some(AuthResult::Refused(reason)) => {
+                    if let Some(ip) = worker.ip() {
+                        self.report(Subject::Ip(ip), Event::LoginFailure);
+                    }
+                    self.report(Subject::Login(name.account.clone()), Event::LoginFailure);
+                    worker.reject_login(RpcError {
+                        code: -32000,
+                        message: reason,
+                    });
+                    return;
+                }
+                // Not the miner's fault, answer without counting it as a failure
+                Some(AuthResult::Unavailable) => {
+                    worker.defer_login(RpcError {
+                        code: -32000,
+                        message: "Unable to authorize login, try again later".to_string(),
+                    });
+                    return;
+                }
+                None => {
+                    self.awaiting_auth.insert(worker.id());
+                    return;
+                }
+            },
+            None => 0,
+        };
+        // Logging in again to the same account is already counted
+        if !(worker.logged_in() && worker.account() == name.account) {
+            match self.limits.admit_login(&name.account) {
+                Ok(_) => {
+                    if worker.logged_in() {
+                        self.limits.release_login(&worker.account());
+                    }
+                }
+                Err(reason) => {
+                    worker.reject_login(RpcError {
+                        code: -32000,
+                        message: reason,
+                    });
+                    return;
+                }
+            }
+        }
+        worker.set_options(&options);
+        let _ = worker.accept_login(user_id, &name);
     }
 
     fn accept_new_job(&mut self) {
-        if self.job.pre_pow != self.server.job.pre_pow {
+        let server_job = self.servers[self.active].job.clone();
+        if self.job.pre_pow != server_job.pre_pow {
+            // Forget the jobs from the last block
+            if server_job.height != self.job.height {
+                self.jobs = HashMap::new();
+            }
             // Use the new job
-            self.job = self.server.job.clone();
+            self.job = server_job;
+            self.jobs.insert(self.job.job_id, self.job.clone());
             // broadcast it to the workers
             let _ = self.broadcast_job();
             // clear last block duplicates map
@@ -221,129 +790,320 @@ impl Pool {
         }
     }
 
-    fn find_share_difficulty(&mut self, share: SubmitParams) -> u64 {
-        // https://github.com/mimblewimble/grin/blob/2fa32d15ce5031aff9d3366b49c3aaca40adbdce/chain/src/pipe.rs#L280
-        // XXX TODO: This
-        // if header.pow.to_difficulty() < target_difficulty {
-        //	return Err(Error::DifficultyTooLow);
-        // }
-        // XXX Currently share difficulty is checked by the grin stratum server, and logged in the grin.log
-        return 0;
+    // Difficulty of a share as the grin node would compute it
+    // https://github.com/mimblewimble/grin/blob/2fa32d15ce5031aff9d3366b49c3aaca40adbdce/chain/src/pipe.rs#L280
+    fn find_share_difficulty(&self, share: &SubmitParams, pre_pow: &str) -> u64 {
+        match pow::share_difficulty(
+            pre_pow,
+            share.get_height() as u64,
+            share.get_edgebits(),
+            &share.pow,
+        ) {
+            Ok(difficulty) => difficulty,
+            Err(e) => {
+                warn!(
+                    LOGGER,
+                    "{} - Unable to find share difficulty: {}", self.id, e
+                );
+                0
+            }
+        }
     }
 
     //
-    // Process shares returned by each workers
-    fn process_shares(&mut self) {
-        let mut workers_l = self.workers.lock().unwrap();
-        for worker in workers_l.iter_mut() {
-            match worker.get_shares().unwrap() {
-                None => {}
-                Some(shares) => {
-                    for share in shares {
-                        //  Check for duplicate or add to duplicate map
-                        if self.duplicates.contains_key(&share.pow) {
+    // Process shares returned by a worker
+    fn process_shares(&mut self, worker: &mut Worker) {
+        match worker.get_shares().unwrap() {
+            None => {}
+            Some(shares) => {
+                for (request_id, share) in shares {
+                    //  Check for duplicate or add to duplicate map
+                    if self.duplicates.contains_key(&share.pow) {
+                        debug!(
+                            LOGGER,
+                            "{} - Rejected duplicate share from worker {} with login {}",
+                            self.id,
+                            worker.id(),
+                            worker.login(),
+                        );
+                        worker.status.rejected += 1;
+                        worker.block_status.rejected += 1;
+                        self.report_worker(worker, Event::DuplicateShare);
+                        // Dont process this share anymore, but send information to kafka
+                        self.reject_share(worker, request_id, &share, SubmitResult::Duplicate, 0);
+                        continue;
+                    } else {
+                        self.duplicates.insert(share.pow.clone(), worker.id());
+                    }
+                    // Verify this share comes from a job we sent for the current block
+                    let pre_pow = match self.jobs.get(&share.job_id) {
+                        Some(job) if job.height == share.get_height() as u64 => job.pre_pow.clone(),
+                        _ => {
                             debug!(
                                 LOGGER,
-                                "{} - Rejected duplicate share from worker {} with login {}",
+                                "{} - Rejected stale share from worker {} with login {}",
                                 self.id,
                                 worker.id(),
                                 worker.login(),
                             );
+                            worker.status.stale += 1;
+                            worker.block_status.stale += 1;
+                            self.reject_share(worker, request_id, &share, SubmitResult::Stale, 0);
+                            continue; // Dont process this share anymore
+                        }
+                    };
+                    // A miner that chose its edge_bits only submits that size
+                    match worker.edge_bits() {
+                        Some(edge_bits) if edge_bits != share.get_edgebits() => {
+                            debug!(
+                                LOGGER,
+                                "{} - Rejected share with edge_bits {} from worker {} with login {}",
+                                self.id,
+                                share.get_edgebits(),
+                                worker.id(),
+                                worker.login(),
+                            );
                             worker.status.rejected += 1;
                             worker.block_status.rejected += 1;
-                            // Dont process this share anymore, but send information to kafka
-
-                            let send_share = Share::new(
-                                share.job_id,
-                                self.server.get_id(),
-                                worker.addr.clone(),
-                                worker.id,
-                                worker.status.difficulty,
+                            self.reject_share(worker, request_id, &share, SubmitResult::Reject, 0);
+                            continue; // Dont process this share anymore
+                        }
+                        _ => {}
+                    }
+                    // Verify the solution so garbage never reaches the upstream server
+                    match pow::verify(&pre_pow, share.nonce, share.get_edgebits(), &share.pow) {
+                        Ok(_) => {}
+                        Err(e) => {
+                            debug!(
+                                LOGGER,
+                                "{} - Rejected invalid share from worker {} with login {}: {}",
+                                self.id,
+                                worker.id(),
                                 worker.login(),
+                                e,
+                            );
+                            worker.status.rejected += 1;
+                            worker.block_status.rejected += 1;
+                            self.report_worker(worker, Event::InvalidShare);
+                            self.reject_share(worker, request_id, &share, SubmitResult::Invalid, 0);
+                            continue; // Dont process this share anymore
+                        }
+                    }
+                    // Check the share meets the difficulty we asked this worker for
+                    let share_diff = self.find_share_difficulty(&share, &pre_pow);
+                    if pow::unscaled_difficulty(share.get_edgebits(), &share.pow)
+                        < worker.share_target(&share)
+                    {
+                        debug!(
+                            LOGGER,
+                            "{} - Rejected low difficulty share from worker {} with login {}",
+                            self.id,
+                            worker.id(),
+                            worker.login(),
+                        );
+                        worker.status.rejected += 1;
+                        worker.block_status.rejected += 1;
+                        self.reject_share(
+                            worker,
+                            request_id,
+                            &share,
+                            SubmitResult::LowDifficulty,
+                            share_diff,
+                        );
+                        continue; // Dont process this share anymore
+                    }
+                    // Send it to the upstream server for further verification and logging
+                    match self.servers[self.active].submit_share(
+                        &share,
+                        request_id.clone(),
+                        worker,
+                        share_diff,
+                    ) {
+                        Ok(_) => {
+                            worker.share_accepted();
+                            self.report_worker(worker, Event::ValidShare);
+                        }
+                        Err(e) => {
+                            error!(
+                                LOGGER,
+                                "{} - Failed to submit share upstream: {}", self.id, e
+                            );
+                            self.reject_share(
+                                worker,
+                                request_id,
+                                &share,
                                 SubmitResult::Reject,
-                                share.get_height(),
-                                Utc::now().timestamp() as u32,
+                                share_diff,
                             );
-                            self.server
-                                .get_kafka()
-                                .send_data(share.get_edgebits(), send_share);
                             continue;
-                        } else {
-                            self.duplicates.insert(share.pow.clone(), worker.id());
                         }
-                        // XXX TODO:
-                        // Verify the timestamp matches what we sent so we know
-                        //   this share comes from the job we sent
-                        // XXX TO DO This I need to deserialize the block header
-                        //			if share.pre_pow != self.current_block {
-                        //			    debug!(
-                        //                                LOGGER,
-                        //                                "{} - Rejected corrupt share from worker {} with login {}",
-                        //                                self.id,
-                        //                                worker.id(),
-                        //                                worker.login(),
-                        //                            );
-                        //                            worker.status.rejected += 1;
-                        //                            worker.block_status.rejected += 1;
-                        //                            continue; // Dont process this share anymore
-                        //                        }
-                        // We dont know the difficulty so we cant check that here
-                        // Send it to the upstream server for further verification and logging
-                        self.server.submit_share(&share.clone(), worker.id());
-                        warn!(LOGGER, "{} - Got share at height {} with nonce {} with difficulty {} from worker {}",
-                                self.id,
-                                self.job.height,
-                                share.nonce,
-                                worker.status.difficulty,
-                                worker.login(),
-                        );
                     }
+                    // The format of this line is parsed by logstash/poolsharefilter.conf
+                    warn!(LOGGER, "{} - Got share at height {} with nonce {} with difficulty {} from worker {}",
+                            self.id,
+                            self.job.height,
+                            share.nonce,
+                            worker.share_target(&share),
+                            worker.login(),
+                    );
+                    debug!(
+                        LOGGER,
+                        "{} - Share from worker {} solved difficulty {}",
+                        self.id,
+                        worker.login(),
+                        share_diff
+                    );
                 }
             }
         }
     }
 
+    // Answer the worker with the reason its share was rejected and record it
+    fn reject_share(
+        &mut self,
+        worker: &mut Worker,
+        request_id: Value,
+        share: &SubmitParams,
+        result: SubmitResult,
+        share_diff: u64,
+    ) {
+        let _ = worker.send_error(
+            "submit".to_string(),
+            RpcError::for_share(result),
+            request_id,
+        );
+        self.send_share_to_kafka(worker, share, result, share_diff);
+    }
+
+    // Record a share the pool handled itself (never sent upstream)
+    fn send_share_to_kafka(
+        &mut self,
+        worker: &Worker,
+        share: &SubmitParams,
+        result: SubmitResult,
+        share_diff: u64,
+    ) {
+        let send_share = Share::new(
+            share.job_id,
+            self.servers[self.active].get_id(),
+            worker.addr.clone(),
+            worker.user_id(),
+            worker.share_target(share),
+            worker.login(),
+            result,
+            share.get_height(),
+            Utc::now().timestamp() as u32,
+            share_diff,
+        );
+        match self.kafka.send_data(share.get_edgebits(), send_share) {
+            Ok(_) => {}
+            Err(e) => {
+                error!(LOGGER, "{} - Failed to publish share: {}", self.id, e);
+            }
+        }
+    }
+
     fn broadcast_job(&mut self) -> Result<(), String> {
-        let mut workers_l = self.workers.lock().unwrap();
         debug!(
             LOGGER,
             "{} - broadcasting a job to {} workers",
             self.id,
-            workers_l.len()
+            self.workers.len()
         );
         // XXX TODO: To do this I need to deserialize the block header
         // XXX TODO: need to randomize the nonce (just in case a miner forgets)
         // XXX TODO: need to set a unique timestamp and record it in the worker struct
-        for num in 0..workers_l.len() {
-            workers_l[num].set_difficulty(1); // XXX TODO: this get from config?
-            workers_l[num].set_height(self.job.height);
-            workers_l[num].send_job(&mut self.job.clone());
+        for worker in self.workers.values_mut() {
+            worker.set_height(self.job.height);
+            let _ = worker.send_job(&mut self.job.clone());
         }
+        // Delete workers we failed to send the job to
+        let _num_active_workers = self.clean_workers();
         return Ok(());
     }
 
-    // Purge dead/sick workers - remove all workers marked in error state
-    fn clean_workers(&mut self) -> usize {
-        let mut start = 0;
-        let mut workers_l = self.workers.lock().unwrap();
-        loop {
-            for num in start..workers_l.len() {
-                if workers_l[num].error() == true {
-                    warn!(
-                        LOGGER,
-                        "{} - Dropping worker: {}",
-                        self.id,
-                        workers_l[num].id()
-                    );
-                    // Remove the dead worker
-                    workers_l.remove(num);
-                    break;
-                }
-                start = num + 1;
+    // Periodic housekeeping for upstream servers and all workers
+    fn tick(&mut self) {
+        self.reload_certificate();
+        self.refresh_bans();
+        self.reputation.expire();
+        if let Some(ref mut auth) = self.auth {
+            auth.expire();
+        }
+        self.check_servers();
+        self.connect_servers();
+        // Fail over if the server we are mining on stopped sending jobs
+        self.select_server();
+        self.accept_new_job();
+
+        self.expire_sessions();
+
+        // Retarget workers whose share rate has dropped off
+        for worker in self.workers.values_mut() {
+            if worker.retarget_difficulty() {
+                let _ = worker.send_job(&mut self.job.clone());
             }
-            if start >= workers_l.len() {
-                return workers_l.len();
+        }
+        let _num_active_workers = self.clean_workers();
+    }
+
+    // Close worker sessions that missed a deadline
+    fn expire_sessions(&mut self) {
+        let now = Instant::now();
+        for worker in self.workers.values_mut() {
+            if worker.error() {
+                continue;
+            }
+            if let Some(timeout) = worker.missed_deadline(&self.timeouts, now) {
+                self.timeout_stats.record(timeout);
+                warn!(
+                    LOGGER,
+                    "{} - Closing worker {} at {}: {:?} timeout ({} login, {} idle, {} share timeouts so far)",
+                    self.id,
+                    worker.id(),
+                    worker.addr,
+                    timeout,
+                    self.timeout_stats.login,
+                    self.timeout_stats.idle,
+                    self.timeout_stats.share
+                );
+                let _ = worker.send_error("".to_string(), timeout.error(), Value::Null);
+                worker.set_error();
             }
         }
     }
+
+    // Purge dead/sick workers - remove all workers marked in error state
+    fn clean_workers(&mut self) -> usize {
+        let id = &self.id;
+        let limits = &mut self.limits;
+        self.workers.retain(|_, worker| {
+            if worker.error() {
+                warn!(LOGGER, "{} - Dropping worker: {}", id, worker.id());
+                limits.release_worker(worker);
+                // Remove the dead worker
+                return false;
+            }
+            true
+        });
+        return self.workers.len();
+    }
+}
+
+// Tell a client why its connection is refused, if the socket will take it
+fn refuse(mut stream: &TcpStream, reason: String) {
+    let error = RpcError {
+        code: -32000,
+        message: reason,
+    };
+    let message = error_response("".to_string(), error, Value::Null) + "\n";
+    let _ = stream.write(message.as_bytes());
+}
+
+// A timeout from the config in seconds, where 0 means none
+fn timeout(secs: Option<u64>, default: u64) -> Option<time::Duration> {
+    match secs.unwrap_or(default) {
+        0 => None,
+        secs => Some(time::Duration::from_secs(secs)),
+    }
 }
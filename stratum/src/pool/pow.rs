@@ -0,0 +1,464 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proof of Work Verification
+//!
+//! Verifies Cuckaroo (secondary) and Cuckatoo (primary) cycles submitted by
+//! workers and computes their difficulty, following the grin node implementation
+//!
+
+use blake2_rfc::blake2b::blake2b;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use rustc_serialize::hex::FromHex;
+use std::cmp::{max, min};
+
+/// Number of edges in a valid cycle
+pub const PROOF_SIZE: usize = 42;
+/// Edge bits of the secondary (Cuckaroo) proof of work
+pub const SECONDARY_EDGE_BITS: u32 = 29;
+/// Smallest edge bits of the primary (Cuckatoo) proof of work
+pub const MIN_PRIMARY_EDGE_BITS: u32 = 31;
+/// Largest edge bits we will try to verify, miners solve C31 and C32
+pub const MAX_PRIMARY_EDGE_BITS: u32 = 32;
+
+// Graph weight scaling of the primary proof of work
+const BASE_EDGE_BITS: u32 = 24;
+const WEEK_HEIGHT: u64 = 7 * 24 * 60;
+const YEAR_HEIGHT: u64 = 52 * WEEK_HEIGHT;
+
+// Cuckaroo hashes edges in blocks of 64
+const SIPHASH_BLOCK_BITS: u64 = 6;
+const SIPHASH_BLOCK_SIZE: u64 = 1 << SIPHASH_BLOCK_BITS;
+const SIPHASH_BLOCK_MASK: u64 = SIPHASH_BLOCK_SIZE - 1;
+
+// ----------------------------------------
+// Siphash
+
+macro_rules! rotl {
+    ($num:expr, $shift:expr) => {
+        $num = $num.rotate_left($shift as u32);
+    };
+}
+
+struct SipHash24(u64, u64, u64, u64);
+
+impl SipHash24 {
+    fn new(v: &[u64; 4]) -> SipHash24 {
+        SipHash24(v[0], v[1], v[2], v[3])
+    }
+
+    fn hash(&mut self, nonce: u64, rot_e: u8) {
+        self.3 ^= nonce;
+        self.round(rot_e);
+        self.round(rot_e);
+
+        self.0 ^= nonce;
+        self.2 ^= 0xff;
+
+        for _ in 0..4 {
+            self.round(rot_e);
+        }
+    }
+
+    fn digest(&self) -> u64 {
+        (self.0 ^ self.1) ^ (self.2 ^ self.3)
+    }
+
+    fn round(&mut self, rot_e: u8) {
+        self.0 = self.0.wrapping_add(self.1);
+        self.2 = self.2.wrapping_add(self.3);
+        rotl!(self.1, 13);
+        rotl!(self.3, 16);
+        self.1 ^= self.0;
+        self.3 ^= self.2;
+        rotl!(self.0, 32);
+        self.2 = self.2.wrapping_add(self.1);
+        self.0 = self.0.wrapping_add(self.3);
+        rotl!(self.1, 17);
+        rotl!(self.3, rot_e);
+        self.1 ^= self.2;
+        self.3 ^= self.0;
+        rotl!(self.2, 32);
+    }
+}
+
+// Siphash-2-4 of a single nonce
+fn siphash24(v: &[u64; 4], nonce: u64) -> u64 {
+    let mut siphash = SipHash24::new(v);
+    siphash.hash(nonce, 21);
+    siphash.digest()
+}
+
+// Siphash of a nonce xored with the hash of the last nonce in its block
+fn siphash_block(v: &[u64; 4], nonce: u64) -> u64 {
+    let nonce0 = nonce & !SIPHASH_BLOCK_MASK;
+    let mut nonce_hash = 0;
+    let mut siphash = SipHash24::new(v);
+    for n in nonce0..(nonce0 + SIPHASH_BLOCK_SIZE) {
+        siphash.hash(n, 21);
+        if n == nonce {
+            nonce_hash = siphash.digest();
+        }
+    }
+    if nonce == nonce0 + SIPHASH_BLOCK_MASK {
+        siphash.digest()
+    } else {
+        nonce_hash ^ siphash.digest()
+    }
+}
+
+/// Build the siphash keys for a job from its pre_pow and the workers nonce
+pub fn create_siphash_keys(pre_pow: &[u8], nonce: u64) -> [u64; 4] {
+    let mut header = pre_pow.to_vec();
+    let mut nonce_bytes = [0u8; 8];
+    BigEndian::write_u64(&mut nonce_bytes, nonce);
+    header.extend_from_slice(&nonce_bytes);
+    let hash = blake2b(32, &[], &header);
+    let hash = hash.as_bytes();
+    [
+        LittleEndian::read_u64(&hash[0..8]),
+        LittleEndian::read_u64(&hash[8..16]),
+        LittleEndian::read_u64(&hash[16..24]),
+        LittleEndian::read_u64(&hash[24..32]),
+    ]
+}
+
+// ----------------------------------------
+// Cycle Verification
+
+// Check the edges are in range and strictly ascending
+fn check_edges(edges: &[u64], edge_mask: u64) -> Result<(), String> {
+    for n in 0..edges.len() {
+        if edges[n] > edge_mask {
+            return Err("edge too big".to_string());
+        }
+        if n > 0 && edges[n] <= edges[n - 1] {
+            return Err("edges not ascending".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Walk the cycle through the edge endpoints in uvs
+fn follow_cycle<F>(uvs: &[u64], same_node: F, cuckatoo: bool) -> Result<(), String>
+where
+    F: Fn(u64, u64) -> bool,
+{
+    let size = uvs.len() / 2;
+    let mut n = 0;
+    let mut i = 0;
+    let mut j;
+    loop {
+        j = i;
+        let mut k = j;
+        loop {
+            k = (k + 2) % (2 * size);
+            if k == i {
+                break;
+            }
+            if same_node(uvs[k], uvs[i]) {
+                // find other edge endpoint matching one at i
+                if j != i {
+                    return Err("branch in cycle".to_string());
+                }
+                j = k;
+            }
+        }
+        if j == i || (cuckatoo && uvs[j] == uvs[i]) {
+            return Err("cycle dead ends".to_string());
+        }
+        i = j ^ 1;
+        n += 1;
+        if i == 0 {
+            break;
+        }
+    }
+    if n == size {
+        Ok(())
+    } else {
+        Err("cycle too short".to_string())
+    }
+}
+
+/// Verify a Cuckaroo cycle
+pub fn verify_cuckaroo(keys: &[u64; 4], edge_bits: u32, edges: &[u64]) -> Result<(), String> {
+    let edge_mask = (1u64 << edge_bits) - 1;
+    check_edges(edges, edge_mask)?;
+    let mut uvs = vec![0u64; 2 * edges.len()];
+    let mut xor0: u64 = 0;
+    let mut xor1: u64 = 0;
+    for n in 0..edges.len() {
+        let edge = siphash_block(keys, edges[n]);
+        uvs[2 * n] = edge & edge_mask;
+        uvs[2 * n + 1] = (edge >> 32) & edge_mask;
+        xor0 ^= uvs[2 * n];
+        xor1 ^= uvs[2 * n + 1];
+    }
+    if xor0 | xor1 != 0 {
+        return Err("endpoints don't match up".to_string());
+    }
+    follow_cycle(&uvs, |a, b| a == b, false)
+}
+
+/// Verify a Cuckatoo cycle
+pub fn verify_cuckatoo(keys: &[u64; 4], edge_bits: u32, edges: &[u64]) -> Result<(), String> {
+    let edge_mask = (1u64 << edge_bits) - 1;
+    check_edges(edges, edge_mask)?;
+    let mut uvs = vec![0u64; 2 * edges.len()];
+    let mut xor0: u64 = (edges.len() as u64 / 2) & 1;
+    let mut xor1: u64 = xor0;
+    for n in 0..edges.len() {
+        uvs[2 * n] = siphash24(keys, 2 * edges[n]) & edge_mask;
+        uvs[2 * n + 1] = siphash24(keys, 2 * edges[n] + 1) & edge_mask;
+        xor0 ^= uvs[2 * n];
+        xor1 ^= uvs[2 * n + 1];
+    }
+    if xor0 | xor1 != 0 {
+        return Err("endpoints don't match up".to_string());
+    }
+    follow_cycle(&uvs, |a, b| a >> 1 == b >> 1, true)
+}
+
+fn decode_pre_pow(pre_pow: &str) -> Result<Vec<u8>, String> {
+    match pre_pow.from_hex() {
+        Ok(p) => Ok(p),
+        Err(e) => Err(format!("invalid pre_pow: {}", e)),
+    }
+}
+
+/// Verify a workers solution for a job
+pub fn verify(pre_pow: &str, nonce: u64, edge_bits: u32, pow: &[u32]) -> Result<(), String> {
+    if pow.len() != PROOF_SIZE {
+        return Err("wrong cycle length".to_string());
+    }
+    let pre_pow = decode_pre_pow(pre_pow)?;
+    let keys = create_siphash_keys(&pre_pow, nonce);
+    let edges: Vec<u64> = pow.iter().map(|e| *e as u64).collect();
+    match edge_bits {
+        SECONDARY_EDGE_BITS => verify_cuckaroo(&keys, edge_bits, &edges),
+        MIN_PRIMARY_EDGE_BITS..=MAX_PRIMARY_EDGE_BITS => verify_cuckatoo(&keys, edge_bits, &edges),
+        _ => Err(format!("unsupported edge_bits {}", edge_bits)),
+    }
+}
+
+// ----------------------------------------
+// Difficulty
+
+// Hash of a proof - the nonces packed into edge_bits bits each
+fn proof_hash(edge_bits: u32, pow: &[u32]) -> u64 {
+    let nonce_bits = edge_bits as usize;
+    let mut bits = vec![0u8; (nonce_bits * pow.len()).div_ceil(8)];
+    for (n, nonce) in pow.iter().enumerate() {
+        for bit in 0..nonce_bits {
+            if (*nonce as u64) & (1 << bit) != 0 {
+                let pos = n * nonce_bits + bit;
+                bits[pos / 8] |= 1 << (pos % 8) as u8;
+            }
+        }
+    }
+    let hash = blake2b(32, &[], &bits);
+    BigEndian::read_u64(&hash.as_bytes()[0..8])
+}
+
+fn scaled_difficulty(scale: u64, hash: u64) -> u64 {
+    let diff = ((scale as u128) << 64) / (max(1, hash) as u128);
+    min(diff, u64::max_value() as u128) as u64
+}
+
+/// Weight of a primary proof of work graph at a height
+pub fn graph_weight(height: u64, edge_bits: u32) -> u64 {
+    let mut xpr_edge_bits = edge_bits as u64;
+    let bits_over_min = edge_bits.saturating_sub(MIN_PRIMARY_EDGE_BITS);
+    let expiry_height = (1 << bits_over_min) * YEAR_HEIGHT;
+    if height >= expiry_height {
+        xpr_edge_bits = xpr_edge_bits.saturating_sub(1 + (height - expiry_height) / WEEK_HEIGHT);
+    }
+    (2 << (edge_bits - BASE_EDGE_BITS) as u64) * xpr_edge_bits
+}
+
+/// Secondary proof of work scaling factor, the last field of pre_pow
+pub fn secondary_scaling(pre_pow: &[u8]) -> u32 {
+    if pre_pow.len() < 4 {
+        return 0;
+    }
+    BigEndian::read_u32(&pre_pow[pre_pow.len() - 4..])
+}
+
+/// Difficulty of a proof before scaling - the difficulty a worker is asked for
+pub fn unscaled_difficulty(edge_bits: u32, pow: &[u32]) -> u64 {
+    scaled_difficulty(1, proof_hash(edge_bits, pow))
+}
+
+/// Difficulty of a proof scaled the same way the grin node scales block difficulty
+pub fn share_difficulty(
+    pre_pow: &str,
+    height: u64,
+    edge_bits: u32,
+    pow: &[u32],
+) -> Result<u64, String> {
+    let scale = match edge_bits {
+        SECONDARY_EDGE_BITS => secondary_scaling(&decode_pre_pow(pre_pow)?) as u64,
+        MIN_PRIMARY_EDGE_BITS..=MAX_PRIMARY_EDGE_BITS => graph_weight(height, edge_bits),
+        _ => return Err(format!("unsupported edge_bits {}", edge_bits)),
+    };
+    Ok(scaled_difficulty(scale, proof_hash(edge_bits, pow)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Test vectors from the grin node
+
+    // Cuckaroo19 keys and solution
+    const CUCKAROO19_KEYS: [u64; 4] = [
+        0x23796193872092ea,
+        0xf1017d8a68c4b745,
+        0xd312bd53d2cd307b,
+        0x840acce5833ddc52,
+    ];
+    const CUCKAROO19_SOL: [u64; 42] = [
+        0x45e9, 0x6a59, 0xf1ad, 0x10ef7, 0x129e8, 0x13e58, 0x17936, 0x19f7f, 0x208df, 0x23704,
+        0x24564, 0x27e64, 0x2b828, 0x2bb41, 0x2ffc0, 0x304c5, 0x31f2a, 0x347de, 0x39686, 0x3ab6c,
+        0x429ad, 0x45254, 0x49200, 0x4f8f8, 0x5697f, 0x57ad1, 0x5dd47, 0x607f8, 0x66199, 0x686c7,
+        0x6d5f3, 0x6da7a, 0x6dbdf, 0x6f6bf, 0x6ffbb, 0x7580e, 0x78594, 0x785ac, 0x78b1d, 0x7b80d,
+        0x7c11c, 0x7da35,
+    ];
+
+    // Cuckatoo29 and Cuckatoo31 solutions for an 80 byte zero header whose
+    // last 4 bytes are replaced by a little endian u32 nonce, 20 and 99.  Here
+    // that is a 72 byte pre_pow and the nonce in the low half of the u64.
+    const CUCKATOO29_NONCE: u64 = 0x14000000;
+    const CUCKATOO29_SOL: [u32; 42] = [
+        0x48a9e2, 0x9cf043, 0x155ca30, 0x18f4783, 0x248f86c, 0x2629a64, 0x5bad752, 0x72e3569,
+        0x93db760, 0x97d3b37, 0x9e05670, 0xa315d5a, 0xa3571a1, 0xa48db46, 0xa7796b6, 0xac43611,
+        0xb64912f, 0xbb6c71e, 0xbcc8be1, 0xc38a43a, 0xd4faa99, 0xe018a66, 0xe37e49c, 0xfa975fa,
+        0x11786035, 0x1243b60a, 0x12892da0, 0x141b5453, 0x1483c3a0, 0x1505525e, 0x1607352c,
+        0x16181fe3, 0x17e3a1da, 0x180b651e, 0x1899d678, 0x1931b0bb, 0x19606448, 0x1b041655,
+        0x1b2c20ad, 0x1bd7a83c, 0x1c05d5b0, 0x1c0b9caa,
+    ];
+    const CUCKATOO31_NONCE: u64 = 0x63000000;
+    const CUCKATOO31_SOL: [u32; 42] = [
+        0x1128e07, 0xc181131, 0x110fad36, 0x1135ddee, 0x1669c7d3, 0x1931e6ea, 0x1c0005f3,
+        0x1dd6ecca, 0x1e29ce7e, 0x209736fc, 0x2692bf1a, 0x27b85aa9, 0x29bb7693, 0x2dc2a047,
+        0x2e28650a, 0x2f381195, 0x350eb3f9, 0x3beed728, 0x3e861cbc, 0x41448cc1, 0x41f08f6d,
+        0x42fbc48a, 0x4383ab31, 0x4389c61f, 0x4540a5ce, 0x49a17405, 0x50372ded, 0x512f0db0,
+        0x588b6288, 0x5a36aa46, 0x5c29e1fe, 0x6118ab16, 0x634705b5, 0x6633d190, 0x6683782f,
+        0x6728b6e1, 0x67adfb45, 0x68ae2306, 0x6d60f5e1, 0x78af3c4f, 0x7dde51ab, 0x7faced21,
+    ];
+
+    #[test]
+    fn test_cuckaroo_vector() {
+        assert_eq!(
+            verify_cuckaroo(&CUCKAROO19_KEYS, 19, &CUCKAROO19_SOL),
+            Ok(())
+        );
+        let mut sol = CUCKAROO19_SOL;
+        sol[20] += 1;
+        assert!(verify_cuckaroo(&CUCKAROO19_KEYS, 19, &sol).is_err());
+    }
+
+    #[test]
+    fn test_cuckatoo_vectors() {
+        let pre_pow = "00".repeat(72);
+        let keys = create_siphash_keys(&[0u8; 72], CUCKATOO29_NONCE);
+        let edges: Vec<u64> = CUCKATOO29_SOL.iter().map(|e| *e as u64).collect();
+        assert_eq!(verify_cuckatoo(&keys, 29, &edges), Ok(()));
+
+        assert_eq!(
+            verify(&pre_pow, CUCKATOO31_NONCE, 31, &CUCKATOO31_SOL),
+            Ok(())
+        );
+        assert_eq!(unscaled_difficulty(31, &CUCKATOO31_SOL), 2);
+        assert_eq!(
+            share_difficulty(&pre_pow, 1, 31, &CUCKATOO31_SOL),
+            Ok(22044)
+        );
+        // Another nonce or edge_bits is a different graph
+        assert!(verify(&pre_pow, CUCKATOO31_NONCE + 1, 31, &CUCKATOO31_SOL).is_err());
+        assert!(verify(&pre_pow, CUCKATOO31_NONCE, 32, &CUCKATOO31_SOL).is_err());
+    }
+
+    #[test]
+    fn test_siphash24() {
+        assert_eq!(siphash24(&[1, 2, 3, 4], 10), 928382149599306901);
+        assert_eq!(siphash24(&[1, 2, 3, 4], 111), 10524991083049122233);
+        assert_eq!(siphash24(&[9, 7, 6, 7], 12), 1305683875471634734);
+        assert_eq!(siphash24(&[9, 7, 6, 7], 10), 11589833042187638814);
+    }
+
+    #[test]
+    fn test_siphash_block() {
+        assert_eq!(siphash_block(&[1, 2, 3, 4], 10), 1182162244994096396);
+        assert_eq!(siphash_block(&[1, 2, 3, 4], 123), 11303676240481718781);
+        assert_eq!(siphash_block(&[9, 7, 6, 7], 12), 4886136884237259030);
+    }
+
+    #[test]
+    fn test_graph_weight() {
+        assert_eq!(graph_weight(1, 31), 256 * 31);
+        assert_eq!(graph_weight(1, 32), 512 * 32);
+        // C31 weight starts to decay after a year
+        assert_eq!(graph_weight(YEAR_HEIGHT, 31), 256 * 30);
+        assert_eq!(graph_weight(YEAR_HEIGHT + WEEK_HEIGHT, 31), 256 * 29);
+    }
+
+    #[test]
+    fn test_share_difficulty() {
+        let pow: Vec<u32> = (1..43).collect();
+        let unscaled = unscaled_difficulty(31, &pow);
+        let pre_pow = "00".repeat(76) + "00000002";
+        assert_eq!(secondary_scaling(&decode_pre_pow(&pre_pow).unwrap()), 2);
+        assert_eq!(
+            share_difficulty(&pre_pow, 1, 31, &pow),
+            Ok(scaled_difficulty(256 * 31, proof_hash(31, &pow)))
+        );
+        assert!(share_difficulty(&pre_pow, 1, 31, &pow).unwrap() >= unscaled * 256 * 31);
+        assert_eq!(
+            share_difficulty(&pre_pow, 1, 29, &pow),
+            Ok(scaled_difficulty(2, proof_hash(29, &pow)))
+        );
+        assert_eq!(scaled_difficulty(1, 0), u64::max_value());
+        assert_eq!(scaled_difficulty(1, u64::max_value()), 1);
+    }
+
+    #[test]
+    fn test_reject_malformed() {
+        let pre_pow = "00".repeat(80);
+        let mut pow: Vec<u32> = (1..43).collect();
+        assert_eq!(
+            verify(&pre_pow, 0, 29, &pow[1..]),
+            Err("wrong cycle length".to_string())
+        );
+        assert_eq!(
+            verify(&pre_pow, 0, 30, &pow),
+            Err("unsupported edge_bits 30".to_string())
+        );
+        assert!(verify("zz", 0, 29, &pow).is_err());
+        pow.swap(3, 4);
+        assert_eq!(
+            verify(&pre_pow, 0, 31, &pow),
+            Err("edges not ascending".to_string())
+        );
+        pow.swap(3, 4);
+        pow[41] = 1 << 29;
+        assert_eq!(
+            verify(&pre_pow, 0, 29, &pow),
+            Err("edge too big".to_string())
+        );
+        // Random edges are not a cycle
+        pow[41] = 1000;
+        assert!(verify(&pre_pow, 0, 29, &pow).is_err());
+        assert!(verify(&pre_pow, 0, 31, &pow).is_err());
+    }
+}
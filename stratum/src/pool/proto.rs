@@ -19,10 +19,10 @@
 use bufstream::BufStream;
 use serde_json;
 use serde_json::Value;
-use std::io::BufRead;
-use std::io::{ErrorKind, Write};
-use std::net::TcpStream;
 
+use pool::codec::{LineCodec, WriteQueue, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_QUEUED_MESSAGES};
+use pool::connection::Connection;
+use pool::kafka::SubmitResult;
 use pool::logger::LOGGER;
 
 // ----------------------------------------
@@ -31,9 +31,13 @@ use pool::logger::LOGGER;
 
 // XXX TODO: Revisit all uses of "pub" in the protocol structs?
 
+// Request ids may be a string, a number or null and are echoed back unchanged
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RpcRequest {
-    pub id: String,
+    #[serde(default)]
+    pub id: Value,
+    #[serde(default)]
     jsonrpc: String,
     pub method: String,
     pub params: Option<Value>,
@@ -41,7 +45,9 @@ pub struct RpcRequest {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RpcResponse {
-    pub id: String,
+    #[serde(default)]
+    pub id: Value,
+    #[serde(default)]
     jsonrpc: String,
     pub method: String,
     pub result: Option<Value>,
@@ -54,7 +60,26 @@ pub struct RpcError {
     pub message: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl RpcError {
+    /// The error a miner receives for a share that was not accepted
+    pub fn for_share(result: SubmitResult) -> RpcError {
+        let (code, message) = match result {
+            SubmitResult::Duplicate => (-32504, "Duplicate share"),
+            SubmitResult::Stale => (-32503, "Solution submitted too late"),
+            SubmitResult::LowDifficulty => (-32501, "Share rejected due to low difficulty"),
+            SubmitResult::Invalid => (-32502, "Failed to validate solution"),
+            SubmitResult::Syncing => (-32701, "Node is syncing - please wait"),
+            SubmitResult::Lost => (-32000, "No response from the node"),
+            _ => (-32000, "Share rejected"),
+        };
+        RpcError {
+            code: code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LoginParams {
     pub login: String,
     pub pass: String,
@@ -71,17 +96,6 @@ pub struct SubmitParams {
 }
 
 impl SubmitParams {
-    pub fn as_string(&self) -> String {
-        /*
-         * as_string function ordering is
-         * height+job_id+nonce+edge_bits
-         */
-        format!(
-            "{}+{}+{}+{}",
-            self.height, self.job_id, self.nonce, self.edge_bits
-        )
-    }
-
     pub fn get_height(&self) -> i32 {
         self.height as i32
     }
@@ -138,6 +152,8 @@ impl WorkerStatus {
 
 pub struct StratumProtocol {
     id: String,
+    codec: LineCodec,
+    queue: WriteQueue,
 }
 
 impl StratumProtocol {
@@ -145,66 +161,78 @@ impl StratumProtocol {
     pub fn new() -> StratumProtocol {
         StratumProtocol {
             id: String::from("proto"),
+            codec: LineCodec::new(DEFAULT_MAX_MESSAGE_SIZE),
+            queue: WriteQueue::new(DEFAULT_MAX_QUEUED_MESSAGES),
         }
     }
 
+    /// Set the number of messages that may wait to be written
+    pub fn set_max_queued_messages(&mut self, max_messages: usize) {
+        self.queue.set_max_frames(max_messages);
+    }
+
+    /// Set the largest message we accept, in bytes
+    pub fn set_max_message_size(&mut self, max_size: usize) {
+        self.codec.set_max_size(max_size);
+    }
+
     /// Read a message from the stream
     fn read_message(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<Connection>,
     ) -> Result<Option<String>, String> {
         // Read and return a single message or None
-        let mut line = String::new();
-        match stream.read_line(&mut line) {
-            Ok(_) => {
-                // warn!(LOGGER, "XXX DEBUG - line read: {:?}", line);
-                // stream is not returning a proper error on disconnect
-                if line == "" {
-                    return Err(format!("{} - Connection Error: Disconnected", self.id));
-                }
-                return Ok(Some(line));
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                // Not an error, just no messages ready
-                return Ok(None);
-            }
+        match self.codec.read_message(stream) {
+            Ok(message) => Ok(message),
             Err(e) => {
-                error!(LOGGER, "{} - Connection Error: {}", self.id, e);
-                return Err(format!("{}", e));
+                error!(LOGGER, "{} - {}", self.id, e);
+                return Err(e);
             }
         }
     }
 
-    /// Write a message to the stream and flush
+    /// Queue a message and write as much of the queue as the stream accepts
     pub fn write_message(
         &mut self,
         message_in: String,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<Connection>,
     ) -> Result<(), String> {
-        let mut message = message_in.clone();
-        if !message.ends_with("\n") {
-            message += "\n";
+        self.queue_message(message_in, false, stream)
+    }
+
+    fn queue_message(
+        &mut self,
+        message: String,
+        job: bool,
+        stream: &mut BufStream<Connection>,
+    ) -> Result<(), String> {
+        match self.queue.push(&message, job) {
+            Ok(_) => {}
+            Err(e) => {
+                error!(LOGGER, "{} - {}", self.id, e);
+                return Err(e);
+            }
         }
-        match stream.write(message.as_bytes()) {
-            Ok(_) => match stream.flush() {
-                Ok(_) => {}
-                Err(e) => {
-                    error!(LOGGER, "{} - Connection Error: {}", self.id, e);
-                    return Err(format!("{}", e));
-                }
-            },
+        return self.flush(stream);
+    }
+
+    /// Write queued messages, called again when the stream becomes writable
+    pub fn flush(&mut self, stream: &mut BufStream<Connection>) -> Result<(), String> {
+        // Writes bypass the BufStream buffer so a full socket leaves the
+        // unwritten part in our queue
+        match self.queue.flush(stream.get_mut()) {
+            Ok(_) => Ok(()),
             Err(e) => {
-                error!(LOGGER, "{} - Connection Error: {}", self.id, e);
-                return Err(format!("{}", e));
+                error!(LOGGER, "{} - {}", self.id, e);
+                return Err(e);
             }
         }
-        return Ok(());
     }
 
     /// Get a message from the upstream
     pub fn get_message(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<Connection>,
     ) -> Result<Option<String>, String> {
         // XXX TODO: Verify this is a valid message before returning it
         return self.read_message(stream);
@@ -214,7 +242,7 @@ impl StratumProtocol {
     // params is the method parameters in serde_json string
     pub fn send_request(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<Connection>,
         method: String,
         params: Option<Value>,
         worker_id: Option<String>,
@@ -224,7 +252,7 @@ impl StratumProtocol {
             Some(id) => id,
         };
         let req = RpcRequest {
-            id: request_id.clone(),
+            id: Value::String(request_id.clone()),
             jsonrpc: "2.0".to_string(),
             method: method,
             params: Some(serde_json::to_value(params).unwrap()),
@@ -237,51 +265,85 @@ impl StratumProtocol {
             request_id,
             req_str
         );
-        return self.write_message(req_str, stream);
+        // A queued job push that was not sent yet is out of date
+        let job = req.method == "job";
+        return self.queue_message(req_str, job, stream);
     }
 
-    /// Send a Response
+    /// Send a Response to a request, id is the id of the request
     pub fn send_response(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<Connection>,
         method: String,
         result: Value,
-        id: usize,
+        id: Value,
     ) -> Result<(), String> {
         let res = RpcResponse {
-            id: id.to_string(),
+            id: id.clone(),
             jsonrpc: "2.0".to_string(),
             method: method,
             result: Some(result),
             error: None,
         };
         let res_str = serde_json::to_string(&res).unwrap();
-        trace!(
-            LOGGER,
-            "{} for {} - Responding: {}",
-            self.id,
-            id.to_string(),
-            res_str
-        );
+        trace!(LOGGER, "{} for {} - Responding: {}", self.id, id, res_str);
         return self.write_message(res_str, stream);
     }
 
-    /// Send an Error Response
+    /// Send an Error Response to a request, id is the id of the request
     pub fn send_error_response(
         &mut self,
-        stream: &mut BufStream<TcpStream>,
+        stream: &mut BufStream<Connection>,
         method: String,
         error: RpcError,
+        id: Value,
     ) -> Result<(), String> {
+        let res_str = error_response(method, error, id);
+        trace!(LOGGER, "{} - Responding with Error: {}", self.id, res_str);
+        return self.write_message(res_str, stream);
+    }
+}
+
+/// An error response message
+pub fn error_response(method: String, error: RpcError, id: Value) -> String {
+    let res = RpcResponse {
+        id: id,
+        jsonrpc: "2.0".to_string(),
+        method: method,
+        result: None,
+        error: Some(serde_json::to_value(error).unwrap()),
+    };
+    serde_json::to_string(&res).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_ids() {
+        let req: RpcRequest =
+            serde_json::from_str(r#"{"id":"abc","jsonrpc":"2.0","method":"login"}"#).unwrap();
+        assert_eq!(req.id, Value::String("abc".to_string()));
+        let req: RpcRequest =
+            serde_json::from_str(r#"{"id":17,"jsonrpc":"2.0","method":"submit"}"#).unwrap();
+        assert_eq!(req.id, json!(17));
+        let req: RpcRequest = serde_json::from_str(r#"{"id":null,"method":"keepalive"}"#).unwrap();
+        assert_eq!(req.id, Value::Null);
+        let req: RpcRequest = serde_json::from_str(r#"{"method":"status"}"#).unwrap();
+        assert_eq!(req.id, Value::Null);
+    }
+
+    #[test]
+    fn test_response_echoes_id() {
         let res = RpcResponse {
-            id: self.id.clone(),
+            id: json!(17),
             jsonrpc: "2.0".to_string(),
-            method: method,
-            result: None,
-            error: Some(serde_json::to_value(error).unwrap()),
+            method: "submit".to_string(),
+            result: Some(json!("ok")),
+            error: None,
         };
-        let res_str = serde_json::to_string(&res).unwrap();
-        trace!(LOGGER, "{} - Responding with Error: {}", self.id, res_str);
-        return self.write_message(res_str, stream);
+        let v: Value = serde_json::from_str(&serde_json::to_string(&res).unwrap()).unwrap();
+        assert_eq!(v["id"], json!(17));
     }
 }
@@ -0,0 +1,84 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Token Bucket Rate Limiter
+//!
+//! Allows a steady rate of events with bursts up to the bucket size.
+//!
+
+use std::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,  // tokens added per second
+    burst: f64, // most tokens the bucket holds
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub fn new(rate: f64, burst: f64) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            burst: burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token if one is available
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+        let now = bucket.last;
+        // The full burst, then nothing
+        assert!(bucket.take_at(now));
+        assert!(bucket.take_at(now));
+        assert!(bucket.take_at(now));
+        assert!(!bucket.take_at(now));
+        // Refills at the rate
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.take_at(later));
+        assert!(!bucket.take_at(later));
+        // Never more than the burst
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.take_at(much_later));
+        }
+        assert!(!bucket.take_at(much_later));
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Worker Reputation
+//!
+//! Tracks the behaviour of each worker IP address and account over a time
+//! window.  An address or account that crosses one of the configured
+//! thresholds is banned for a while, and each repeat ban lasts twice as long
+//! as the last.  The number of records is capped, when full the least
+//! recently seen subjects without a ban are forgotten first.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use pool::config::ReputationConfig;
+
+const DEFAULT_WINDOW: u64 = 300;
+const DEFAULT_MIN_SHARES: u64 = 20;
+const DEFAULT_MAX_INVALID_RATIO: f64 = 0.5;
+const DEFAULT_MAX_DUPLICATES: u64 = 50;
+const DEFAULT_MAX_MALFORMED: u64 = 50;
+const DEFAULT_MAX_CONNECTS: u64 = 60;
+const DEFAULT_MAX_LOGIN_FAILURES: u64 = 10;
+const DEFAULT_MAX_RATE_LIMITED: u64 = 100;
+const DEFAULT_BAN_TIME: u64 = 300;
+const DEFAULT_MAX_BAN_TIME: u64 = 86400;
+const DEFAULT_FORGET_AFTER: u64 = 86400;
+const DEFAULT_MAX_RECORDS: usize = 100000;
+
+/// Who a reputation belongs to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subject {
+    Ip(IpAddr),
+    Login(String), // the account, shared by all of its workers
+}
+
+impl Subject {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Subject::Ip(_) => "ip",
+            Subject::Login(_) => "login",
+        }
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Subject::Ip(ref ip) => write!(f, "{}", ip),
+            Subject::Login(ref login) => write!(f, "{}", login),
+        }
+    }
+}
+
+/// Something a worker did that affects its reputation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    ValidShare,
+    InvalidShare,
+    DuplicateShare,
+    Malformed,
+    Connect,
+    LoginFailure,
+    RateLimited,
+}
+
+/// A ban issued because a subject crossed a threshold
+#[derive(Debug, Clone)]
+pub struct AutoBan {
+    pub subject: Subject,
+    pub reason: String,
+    pub duration: Duration,
+    pub count: u32, // number of times this subject has been banned
+}
+
+// Counts for the current window
+struct Record {
+    window_start: Instant,
+    last_seen: Instant,
+    valid: u64,
+    invalid: u64,
+    duplicates: u64,
+    malformed: u64,
+    connects: u64,
+    login_failures: u64,
+    rate_limited: u64,
+    bans: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Record {
+    fn new(now: Instant) -> Record {
+        Record {
+            window_start: now,
+            last_seen: now,
+            valid: 0,
+            invalid: 0,
+            duplicates: 0,
+            malformed: 0,
+            connects: 0,
+            login_failures: 0,
+            rate_limited: 0,
+            bans: 0,
+            banned_until: None,
+        }
+    }
+
+    fn reset(&mut self, now: Instant) {
+        self.window_start = now;
+        self.valid = 0;
+        self.invalid = 0;
+        self.duplicates = 0;
+        self.malformed = 0;
+        self.connects = 0;
+        self.login_failures = 0;
+        self.rate_limited = 0;
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        match self.banned_until {
+            Some(until) => now < until,
+            None => false,
+        }
+    }
+}
+
+pub struct Reputation {
+    config: Option<ReputationConfig>,
+    records: HashMap<Subject, Record>,
+}
+
+impl Reputation {
+    pub fn new(config: Option<ReputationConfig>) -> Reputation {
+        Reputation {
+            config: config,
+            records: HashMap::new(),
+        }
+    }
+
+    /// Record an event, returns a ban if the subject crossed a threshold
+    pub fn record(&mut self, subject: Subject, event: Event) -> Option<AutoBan> {
+        self.record_at(subject, event, Instant::now())
+    }
+
+    fn record_at(&mut self, subject: Subject, event: Event, now: Instant) -> Option<AutoBan> {
+        let config = match self.config {
+            Some(ref config) => config,
+            None => return None,
+        };
+        if !self.records.contains_key(&subject) {
+            let max_records = config.max_records.unwrap_or(DEFAULT_MAX_RECORDS).max(1);
+            if self.records.len() >= max_records {
+                evict(&mut self.records, max_records, now);
+            }
+        }
+        let record = self
+            .records
+            .entry(subject.clone())
+            .or_insert_with(|| Record::new(now));
+        record.last_seen = now;
+        if record.is_banned(now) {
+            return None;
+        }
+        let window = Duration::from_secs(config.window.unwrap_or(DEFAULT_WINDOW));
+        if now.duration_since(record.window_start) >= window {
+            record.reset(now);
+        }
+        match event {
+            Event::ValidShare => record.valid += 1,
+            Event::InvalidShare => record.invalid += 1,
+            Event::DuplicateShare => record.duplicates += 1,
+            Event::Malformed => record.malformed += 1,
+            Event::Connect => record.connects += 1,
+            Event::LoginFailure => record.login_failures += 1,
+            Event::RateLimited => record.rate_limited += 1,
+        }
+        let reason = threshold_crossed(config, record)?;
+        // Escalate: each repeat ban is twice as long, up to the limit
+        let ban_time = config.ban_time.unwrap_or(DEFAULT_BAN_TIME);
+        let max_ban_time = config.max_ban_time.unwrap_or(DEFAULT_MAX_BAN_TIME);
+        let duration = ban_time
+            .checked_mul(1u64 << ::std::cmp::min(record.bans, 32))
+            .unwrap_or(max_ban_time);
+        let duration = Duration::from_secs(::std::cmp::min(duration, max_ban_time));
+        record.bans += 1;
+        record.banned_until = Some(now + duration);
+        record.reset(now);
+        Some(AutoBan {
+            subject: subject,
+            reason: reason,
+            duration: duration,
+            count: record.bans,
+        })
+    }
+
+    /// Is the subject currently banned
+    pub fn is_banned(&self, subject: &Subject) -> bool {
+        match self.records.get(subject) {
+            Some(record) => record.is_banned(Instant::now()),
+            None => false,
+        }
+    }
+
+    /// Forget subjects that are not banned and have not been seen for a while
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        let forget_after = match self.config {
+            Some(ref config) => {
+                Duration::from_secs(config.forget_after.unwrap_or(DEFAULT_FORGET_AFTER))
+            }
+            None => return,
+        };
+        self.records.retain(|_, record| {
+            record.is_banned(now) || now.duration_since(record.last_seen) < forget_after
+        });
+    }
+}
+
+// Make room in a full table, a tenth of it at a time so a flood of new
+// subjects does not scan it for each one.  Subjects that were never banned
+// go first, then those with past bans, then the ones banned now, each least
+// recently seen first.
+fn evict(records: &mut HashMap<Subject, Record>, max_records: usize, now: Instant) {
+    let mut subjects: Vec<(bool, bool, Instant, Subject)> = records
+        .iter()
+        .map(|(subject, record)| {
+            (
+                record.is_banned(now),
+                record.bans > 0,
+                record.last_seen,
+                subject.clone(),
+            )
+        })
+        .collect();
+    subjects.sort_by_key(|&(banned, was_banned, last_seen, _)| (banned, was_banned, last_seen));
+    let excess = records.len() + 1 - max_records;
+    let count = ::std::cmp::max(excess, max_records / 10);
+    for (_, _, _, subject) in subjects.into_iter().take(count) {
+        records.remove(&subject);
+    }
+}
+
+// The reason for a ban, if the record crossed any threshold
+fn threshold_crossed(config: &ReputationConfig, record: &Record) -> Option<String> {
+    let shares = record.valid + record.invalid;
+    if shares >= config.min_shares.unwrap_or(DEFAULT_MIN_SHARES) {
+        let ratio = record.invalid as f64 / shares as f64;
+        if ratio
+            > config
+                .max_invalid_ratio
+                .unwrap_or(DEFAULT_MAX_INVALID_RATIO)
+        {
+            return Some(format!(
+                "{} of {} shares were invalid",
+                record.invalid, shares
+            ));
+        }
+    }
+    if record.duplicates > config.max_duplicates.unwrap_or(DEFAULT_MAX_DUPLICATES) {
+        return Some(format!("{} duplicate shares", record.duplicates));
+    }
+    if record.malformed > config.max_malformed.unwrap_or(DEFAULT_MAX_MALFORMED) {
+        return Some(format!("{} malformed requests", record.malformed));
+    }
+    if record.connects > config.max_connects.unwrap_or(DEFAULT_MAX_CONNECTS) {
+        return Some(format!("{} connections", record.connects));
+    }
+    if record.login_failures
+        > config
+            .max_login_failures
+            .unwrap_or(DEFAULT_MAX_LOGIN_FAILURES)
+    {
+        return Some(format!("{} failed logins", record.login_failures));
+    }
+    if record.rate_limited > config.max_rate_limited.unwrap_or(DEFAULT_MAX_RATE_LIMITED) {
+        return Some(format!(
+            "{} requests over the rate limit",
+            record.rate_limited
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pool::naming::NamingRules;
+
+    fn config() -> ReputationConfig {
+        ReputationConfig {
+            window: Some(60),
+            min_shares: Some(4),
+            max_invalid_ratio: Some(0.5),
+            max_duplicates: Some(2),
+            max_malformed: None,
+            max_connects: Some(3),
+            max_login_failures: None,
+            max_rate_limited: None,
+            ban_time: Some(10),
+            max_ban_time: Some(30),
+            forget_after: Some(600),
+            max_records: None,
+        }
+    }
+
+    fn ip() -> Subject {
+        Subject::Ip("10.0.0.1".parse().unwrap())
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut reputation = Reputation::new(None);
+        for _ in 0..1000 {
+            assert!(reputation.record(ip(), Event::InvalidShare).is_none());
+        }
+        assert!(!reputation.is_banned(&ip()));
+    }
+
+    #[test]
+    fn test_invalid_ratio() {
+        let mut reputation = Reputation::new(Some(config()));
+        let now = Instant::now();
+        // Workers report under their account, as the pool does
+        let naming = NamingRules::new(None).unwrap();
+        let rig1 = Subject::Login(naming.parse("user.rig1").unwrap().account);
+        let rig2 = Subject::Login(naming.parse("user.rig2").unwrap().account);
+        let account = Subject::Login("user".to_string());
+        assert!(reputation
+            .record_at(rig1.clone(), Event::ValidShare, now)
+            .is_none());
+        assert!(reputation
+            .record_at(rig2.clone(), Event::InvalidShare, now)
+            .is_none());
+        assert!(reputation
+            .record_at(rig1.clone(), Event::InvalidShare, now)
+            .is_none());
+        // Both workers count toward one record
+        assert_eq!(reputation.records.len(), 1);
+        // 3 of 4 invalid
+        let ban = reputation
+            .record_at(rig2.clone(), Event::InvalidShare, now)
+            .unwrap();
+        assert_eq!(ban.subject, account);
+        assert_eq!(ban.duration, Duration::from_secs(10));
+        assert!(reputation.is_banned(&rig1));
+        assert!(reputation.is_banned(&rig2));
+    }
+
+    #[test]
+    fn test_window() {
+        let mut reputation = Reputation::new(Some(config()));
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(reputation.record_at(ip(), Event::Connect, now).is_none());
+        }
+        // The count starts over in the next window
+        let later = now + Duration::from_secs(60);
+        assert!(reputation.record_at(ip(), Event::Connect, later).is_none());
+        assert!(reputation
+            .record_at(ip(), Event::DuplicateShare, later)
+            .is_none());
+    }
+
+    #[test]
+    fn test_escalation() {
+        let mut reputation = Reputation::new(Some(config()));
+        let mut now = Instant::now();
+        let mut durations = Vec::new();
+        for _ in 0..4 {
+            let mut ban = None;
+            while ban.is_none() {
+                ban = reputation.record_at(ip(), Event::DuplicateShare, now);
+            }
+            let duration = ban.unwrap().duration;
+            // Events while banned are ignored
+            assert!(reputation
+                .record_at(ip(), Event::DuplicateShare, now)
+                .is_none());
+            durations.push(duration.as_secs());
+            now += duration;
+        }
+        assert_eq!(durations, vec![10, 20, 30, 30]);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut reputation = Reputation::new(Some(config()));
+        let now = Instant::now();
+        reputation.record_at(ip(), Event::Connect, now);
+        reputation.expire_at(now + Duration::from_secs(599));
+        assert_eq!(reputation.records.len(), 1);
+        reputation.expire_at(now + Duration::from_secs(600));
+        assert_eq!(reputation.records.len(), 0);
+    }
+
+    #[test]
+    fn test_max_records() {
+        let mut config = config();
+        config.max_records = Some(3);
+        let mut reputation = Reputation::new(Some(config));
+        let now = Instant::now();
+        // A banned account, and two addresses seen after it
+        let banned = Subject::Login("user".to_string());
+        while reputation
+            .record_at(banned.clone(), Event::DuplicateShare, now)
+            .is_none()
+        {}
+        let old = Subject::Ip("10.0.0.2".parse().unwrap());
+        reputation.record_at(old.clone(), Event::Connect, now + Duration::from_secs(1));
+        reputation.record_at(ip(), Event::Connect, now + Duration::from_secs(2));
+        // A new subject forgets the oldest one without a ban
+        let new = Subject::Ip("10.0.0.3".parse().unwrap());
+        reputation.record_at(new.clone(), Event::Connect, now + Duration::from_secs(3));
+        assert_eq!(reputation.records.len(), 3);
+        assert!(reputation.is_banned(&banned));
+        assert!(!reputation.records.contains_key(&old));
+        assert!(reputation.records.contains_key(&ip()));
+        assert!(reputation.records.contains_key(&new));
+    }
+}
@@ -16,17 +16,19 @@
 //! An upstream grin stratum server
 //!
 
-use base64;
 use bufstream::BufStream;
 use chrono::offset::Utc;
+use mio::net::TcpStream;
+use mio::{Poll, PollOpt, Ready, Token};
 use serde_json;
 use serde_json::Value;
-use std::net::{Shutdown, TcpStream};
-use std::sync::{Arc, Mutex, RwLock};
-use std::{thread, time};
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::time::{Duration, Instant};
 
-use pool::config::{Config, NodeConfig, PoolConfig, WorkerConfig};
-use pool::kafka::{GrinProducer, KafkaProducer, Share, SubmitResult};
+use pool::config::{Config, NodeConfig};
+use pool::connection::{connect_tls, Connection};
+use pool::kafka::{BlockFound, GrinProducer, KafkaProducer, Share, SubmitResult};
 use pool::logger::LOGGER;
 use pool::proto::{
     JobTemplate, LoginParams, RpcError, StratumProtocol, SubmitParams, WorkerStatus,
@@ -34,18 +36,56 @@ use pool::proto::{
 use pool::proto::{RpcRequest, RpcResponse};
 use pool::worker::Worker;
 
+// Seconds to wait for a connection to complete
+const CONNECT_TIMEOUT: u64 = 5;
+// Seconds between connection attempts
+const RECONNECT_DELAY: u64 = 1;
+// Seconds between keepalive / status requests
+const DEFAULT_KEEPALIVE_INTERVAL: u64 = 30;
+// Seconds to wait for a keepalive response before dropping the connection
+const DEFAULT_RESPONSE_TIMEOUT: u64 = 30;
+// Seconds the job height may stay the same before dropping the connection
+const DEFAULT_HEIGHT_TIMEOUT: u64 = 900;
+// Seconds to wait for a submit response before the share is considered lost
+const DEFAULT_SUBMIT_TIMEOUT: u64 = 30;
+
+// ----------------------------------------
+// A share submitted upstream, waiting for its response
+
+struct PendingShare {
+    share: SubmitParams,
+    request_id: Value, // The id the worker submitted the share with
+    worker_id: usize,
+    user_id: i32, // Pool API user the worker logged in as
+    worker_addr: String,
+    login: String,
+    difficulty: u64,
+    share_diff: u64,
+    sent: Instant,
+}
+
 // ----------------------------------------
 // Server Object - our connection to a stratum server - a grin node
 
 pub struct Server {
     id: String,
     config: Config,
-    stream: Option<BufStream<TcpStream>>,
+    node: NodeConfig,
+    stream: Option<BufStream<Connection>>,
     protocol: StratumProtocol,
     error: bool,
+    connecting: bool,
+    connect_started: Instant,
+    next_connect: Instant, // Time of the next (re)connect attempt
     pub job: JobTemplate,
+    last_job: Instant,
+    last_height_change: Instant,
+    last_keepalive: Instant,                // When we last sent a keepalive
+    keepalive_sent: Option<Instant>,        // Keepalive waiting for a response
+    latency: Option<Duration>,              // Round trip time of the last keepalive
+    pending: HashMap<String, PendingShare>, // Submits waiting for a response, by request id
+    next_request_id: u64,
     status: WorkerStatus,
-    kafka: KafkaProducer,
 }
 
 impl Server {
@@ -53,53 +93,248 @@ impl Server {
         self.id.clone()
     }
 
-    pub fn get_kafka(&mut self) -> &mut KafkaProducer {
-        &mut self.kafka
+    /// Address of the upstream grin node
+    pub fn address(&self) -> String {
+        self.node.address.clone() + ":" + &self.node.stratum_port.to_string()
     }
 
     /// Creates a new Stratum Server Connection.
-    pub fn new(cfg: Config) -> Server {
+    pub fn new(cfg: Config, node: NodeConfig) -> Server {
         Server {
-            id: format!("Pool-{}", cfg.server.id.to_string()),
-            kafka: KafkaProducer::from_config(&cfg.producer),
+            id: format!("Pool-{}", cfg.server.id),
             config: cfg,
+            node: node,
             stream: None,
             protocol: StratumProtocol::new(),
             error: false,
+            connecting: false,
+            connect_started: Instant::now(),
+            next_connect: Instant::now(),
             job: JobTemplate::new(),
+            last_job: Instant::now(),
+            last_height_change: Instant::now(),
+            last_keepalive: Instant::now(),
+            keepalive_sent: None,
+            latency: None,
+            pending: HashMap::new(),
+            next_request_id: 0,
             status: WorkerStatus::new("Pool".to_string()),
         }
     }
 
+    /// Is the connection to the upstream server up and logged in
+    pub fn is_connected(&self) -> bool {
+        !self.error && !self.connecting && self.stream.is_some()
+    }
+
+    /// Can we mine on this upstream server - it is connected and sending us jobs
+    pub fn is_healthy(&self, job_timeout: Duration) -> bool {
+        self.is_connected() && self.job.height > 0 && self.last_job.elapsed() < job_timeout
+    }
+
+    /// Is a connection attempt in progress
+    pub fn is_connecting(&self) -> bool {
+        self.connecting
+    }
+
+    /// Wait before trying to connect again
+    pub fn schedule_reconnect(&mut self, delay: Duration) {
+        self.next_connect = Instant::now() + delay;
+    }
+
+    /// Drop the connection, a new one will be made on the next connect()
+    pub fn disconnect(&mut self) {
+        self.error = true;
+        self.connecting = false;
+        self.stream = None;
+    }
+
+    /// Write queued messages, called when the upstream stream becomes writable
+    pub fn flush(&mut self) -> Result<(), String> {
+        match self.stream {
+            Some(ref mut stream) => self.protocol.flush(stream),
+            None => Ok(()),
+        }
+    }
+
+    /// Use a new job from the upstream server
+    fn set_job(&mut self, job: JobTemplate) {
+        if job.height != self.job.height {
+            self.last_height_change = Instant::now();
+        }
+        self.job = job;
+        self.last_job = Instant::now();
+    }
+
+    /// Send keepalive and status requests on a schedule, drop a connection that
+    /// has stopped responding or whose jobs have stopped advancing in height
+    pub fn check_health(&mut self) -> Result<(), String> {
+        if !self.is_connected() {
+            return Ok(());
+        }
+        let keepalive_interval = Duration::from_secs(
+            self.config
+                .server
+                .keepalive_interval
+                .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL),
+        );
+        let response_timeout = Duration::from_secs(
+            self.config
+                .server
+                .response_timeout
+                .unwrap_or(DEFAULT_RESPONSE_TIMEOUT),
+        );
+        let height_timeout = Duration::from_secs(
+            self.config
+                .server
+                .height_timeout
+                .unwrap_or(DEFAULT_HEIGHT_TIMEOUT),
+        );
+        match self.keepalive_sent {
+            Some(sent) => {
+                // A silent or half-open connection never answers
+                if sent.elapsed() > response_timeout {
+                    self.disconnect();
+                    return Err(format!(
+                        "{} - No keepalive response in {:?}",
+                        self.address(),
+                        response_timeout
+                    ));
+                }
+            }
+            None => {
+                if self.last_keepalive.elapsed() >= keepalive_interval {
+                    self.last_keepalive = Instant::now();
+                    self.keepalive_sent = Some(Instant::now());
+                    self.send_keepalive()?;
+                    self.request_status()?;
+                }
+            }
+        }
+        if self.job.height > 0 && self.last_height_change.elapsed() > height_timeout {
+            self.disconnect();
+            return Err(format!(
+                "{} - Job height {} has not advanced in {:?}",
+                self.address(),
+                self.job.height,
+                height_timeout
+            ));
+        }
+        Ok(())
+    }
+
     /// Connect to an upstream Grin Stratum Server
-    /// Request Login and Job Request
-    pub fn connect(&mut self) -> Result<(), String> {
+    /// Start a nonblocking connect and register it with the pools event loop,
+    /// login and job request are sent once the connection completes
+    pub fn connect(&mut self, poll: &Poll, token: Token) -> Result<(), String> {
         // Only connect if we are not already connected
         if !self.error && self.stream.is_some() {
+            // Give up on connection attempts that never complete
+            if self.connecting
+                && self.connect_started.elapsed() > Duration::from_secs(CONNECT_TIMEOUT)
+            {
+                self.disconnect();
+                self.schedule_reconnect(Duration::from_secs(RECONNECT_DELAY));
+                return Err(format!("{} - Connection timed out", self.address()));
+            }
+            return Ok(());
+        }
+        if Instant::now() < self.next_connect {
             return Ok(());
         }
-        let grin_stratum_url = self.config.grin_node.address.clone()
-            + ":"
-            + &self.config.grin_node.stratum_port.to_string();
+        self.schedule_reconnect(Duration::from_secs(RECONNECT_DELAY));
+        let grin_stratum_url = self.address();
         warn!(
             LOGGER,
             "{} - Connecting to upstream stratum server at {}",
             self.id,
             grin_stratum_url.to_string()
         );
-        match TcpStream::connect(grin_stratum_url.to_string()) {
+        let addr = match grin_stratum_url.to_socket_addrs() {
+            Ok(mut addrs) => match addrs.next() {
+                Some(addr) => addr,
+                None => {
+                    self.error = true;
+                    return Err(format!("{} - Unable to resolve address", grin_stratum_url));
+                }
+            },
+            Err(e) => {
+                self.error = true;
+                return Err(e.to_string());
+            }
+        };
+        match TcpStream::connect(&addr) {
             Ok(conn) => {
-                let _ = conn
-                    .set_nonblocking(true)
-                    .expect("set_nonblocking call failed");
-                self.stream = Some(BufStream::new(conn));
+                match poll.register(
+                    &conn,
+                    token,
+                    Ready::readable() | Ready::writable(),
+                    PollOpt::edge(),
+                ) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        self.error = true;
+                        return Err(e.to_string());
+                    }
+                }
+                let connection = if self.node.tls {
+                    match connect_tls(conn, &self.node) {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            self.error = true;
+                            return Err(e);
+                        }
+                    }
+                } else {
+                    Connection::plain(conn)
+                };
+                self.stream = Some(BufStream::new(connection));
                 self.error = false;
+                self.connecting = true;
+                self.connect_started = Instant::now();
             }
             Err(e) => {
                 self.error = true;
                 return Err(e.to_string());
             }
         };
+        return Ok(());
+    }
+
+    /// Complete a nonblocking connect once the event loop reports the socket ready
+    /// Request Login and Job Request
+    pub fn finish_connect(&mut self) -> Result<(), String> {
+        if !self.connecting {
+            return Ok(());
+        }
+        let connected = match self.stream {
+            Some(ref stream) => match stream.get_ref().socket().take_error() {
+                Ok(Some(e)) | Err(e) => Err(e.to_string()),
+                Ok(None) => Ok(stream.get_ref().socket().peer_addr().is_ok()),
+            },
+            None => Err("No upstream connection".to_string()),
+        };
+        match connected {
+            Ok(true) => {}
+            Ok(false) => {
+                // Not connected yet
+                return Ok(());
+            }
+            Err(e) => {
+                self.disconnect();
+                return Err(e);
+            }
+        }
+        warn!(
+            LOGGER,
+            "{} - Connected to upstream stratum server at {}",
+            self.id,
+            self.address()
+        );
+        self.connecting = false;
+        self.last_height_change = Instant::now();
+        self.last_keepalive = Instant::now();
+        self.keepalive_sent = None;
         // Send login
         match self.log_in() {
             Ok(_) => {}
@@ -121,7 +356,7 @@ impl Server {
 
     /// Request status from the upstream Grin Stratum server - this is *pool* status (not individual
     /// worker status)
-    pub fn request_status(&mut self, stream: &mut BufStream<TcpStream>) -> Result<(), String> {
+    pub fn request_status(&mut self) -> Result<(), String> {
         match self.stream {
             Some(ref mut stream) => {
                 trace!(LOGGER, "{} - Requesting status", self.id);
@@ -141,8 +376,8 @@ impl Server {
         match self.stream {
             Some(ref mut stream) => {
                 let login_params = LoginParams {
-                    login: self.config.grin_node.login.clone().to_string(),
-                    pass: self.config.grin_node.password.clone().to_string(),
+                    login: self.node.login.clone().to_string(),
+                    pass: self.node.password.clone().to_string(),
                     agent: self.id.clone(),
                 };
                 let params_value = serde_json::to_value(login_params).unwrap();
@@ -178,26 +413,140 @@ impl Server {
     pub fn submit_share(
         &mut self,
         solution: &SubmitParams,
-        worker_id: usize,
+        worker_request_id: Value,
+        worker: &Worker,
+        share_diff: u64,
     ) -> Result<(), String> {
         match self.stream {
             Some(ref mut stream) => {
                 let params_value = serde_json::to_value(solution).unwrap();
                 debug!(LOGGER, "{} - Submitting a share", self.id);
-                let encode_string: String = base64::encode(
-                    format!("{}+{}", worker_id.to_string(), solution.as_string()).as_bytes(),
-                );
-                return self.protocol.send_request(
+                self.next_request_id += 1;
+                let request_id = self.next_request_id.to_string();
+                self.protocol.send_request(
                     stream,
                     "submit".to_string(),
                     Some(params_value),
-                    Some(encode_string),
+                    Some(request_id.clone()),
+                )?;
+                self.pending.insert(
+                    request_id,
+                    PendingShare {
+                        share: solution.clone(),
+                        request_id: worker_request_id,
+                        worker_id: worker.id(),
+                        user_id: worker.user_id(),
+                        worker_addr: worker.addr.clone(),
+                        login: worker.login(),
+                        difficulty: worker.share_target(solution),
+                        share_diff: share_diff,
+                        sent: Instant::now(),
+                    },
                 );
+                return Ok(());
             }
             None => Err("No upstream connection".to_string()),
         }
     }
 
+    /// Report submitted shares that never got a response as lost
+    pub fn expire_submits(
+        &mut self,
+        workers: &mut HashMap<usize, Worker>,
+        kafka: &mut KafkaProducer,
+    ) {
+        let timeout = Duration::from_secs(
+            self.config
+                .server
+                .submit_timeout
+                .unwrap_or(DEFAULT_SUBMIT_TIMEOUT),
+        );
+        let expired: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|&(_, pending)| pending.sent.elapsed() > timeout)
+            .map(|(request_id, _)| request_id.clone())
+            .collect();
+        for request_id in expired {
+            if let Some(pending) = self.pending.remove(&request_id) {
+                warn!(
+                    LOGGER,
+                    "{} - No response from {} for share {} from worker {}, marking it lost",
+                    self.id,
+                    self.address(),
+                    request_id,
+                    pending.login,
+                );
+                if let Some(worker) = workers.get_mut(&pending.worker_id) {
+                    worker.status.rejected += 1;
+                    let _ = worker.send_error(
+                        "submit".to_string(),
+                        RpcError::for_share(SubmitResult::Lost),
+                        pending.request_id.clone(),
+                    );
+                }
+                self.record_share(kafka, &pending, SubmitResult::Lost);
+            }
+        }
+    }
+
+    // Record a block found by one of our workers in kafka
+    fn record_block(&self, kafka: &mut KafkaProducer, pending: &PendingShare, hash: String) {
+        warn!(
+            LOGGER,
+            "{} - Block found at height {} with hash {} by worker {} ({})",
+            self.id,
+            pending.share.get_height(),
+            hash,
+            pending.login,
+            pending.worker_addr,
+        );
+        let block = BlockFound::new(
+            pending.share.get_height() as u64,
+            hash,
+            pending.share.get_edgebits(),
+            pending.share.job_id,
+            self.id.clone(),
+            pending.worker_id,
+            pending.worker_addr.clone(),
+            pending.login.clone(),
+            Utc::now().timestamp() as u32,
+        );
+        match kafka.send_block(block) {
+            Ok(_) => {}
+            Err(e) => {
+                error!(LOGGER, "{} - Failed to publish found block: {}", self.id, e);
+            }
+        }
+    }
+
+    // Record the outcome of a submitted share in kafka
+    fn record_share(
+        &self,
+        kafka: &mut KafkaProducer,
+        pending: &PendingShare,
+        result: SubmitResult,
+    ) {
+        let share = Share::new(
+            pending.share.job_id,
+            self.id.clone(),
+            pending.worker_addr.clone(),
+            pending.user_id,
+            pending.difficulty,
+            pending.login.clone(),
+            result,
+            pending.share.get_height(),
+            Utc::now().timestamp() as u32,
+            pending.share_diff,
+        );
+        match kafka.send_data(pending.share.get_edgebits(), share) {
+            Ok(_) => {}
+            Err(e) => {
+                error!(LOGGER, "{} - Failed to publish share: {}", self.id, e);
+            }
+        }
+    }
+
     /// Send Keepalive
     pub fn send_keepalive(&mut self) -> Result<(), String> {
         match self.stream {
@@ -218,16 +567,36 @@ impl Server {
     // Method to handle responses from the upstream stratum server
 
     /// Process Messages from the upstream stratum server
+    // Drains every message currently available on the connection
     pub fn process_messages(
         &mut self,
-        workers: &mut Arc<Mutex<Vec<Worker>>>,
+        workers: &mut HashMap<usize, Worker>,
+        kafka: &mut KafkaProducer,
     ) -> Result<String, RpcError> {
         // XXX TODO: With some reasonable rate limiting (like N message per pass)
-        return self.process_message(workers);
+        loop {
+            match self.process_message(workers, kafka) {
+                Ok(ref method) if method == "None" => {
+                    return Ok(method.clone());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // Only stop reading if the connection itself is broken
+                    if self.error || self.stream.is_none() {
+                        return Err(e);
+                    }
+                    error!(
+                        LOGGER,
+                        "{} - Error processing upstream message: {:?}", self.id, e
+                    );
+                }
+            }
+        }
     }
     pub fn process_message(
         &mut self,
-        workers: &mut Arc<Mutex<Vec<Worker>>>,
+        workers: &mut HashMap<usize, Worker>,
+        kafka: &mut KafkaProducer,
     ) -> Result<String, RpcError> {
         // Read a message from the upstream
         // Handle the message
@@ -244,12 +613,18 @@ impl Server {
                                     self.id,
                                     message
                                 );
-                                let v: serde_json::Value = serde_json::from_str(&message).unwrap();
+                                let v: serde_json::Value = match serde_json::from_str(&message) {
+                                    Ok(v) => v,
+                                    Err(e) => return Err(malformed(&message, e)),
+                                };
                                 // Is this a response or request?
                                 // XXX TODO: Is there a better way? Introspection? Check Value for field?
-                                if v["id"] == String::from("Stratum") {
+                                if v["id"] == "Stratum" {
                                     // this is a request
-                                    let req: RpcRequest = serde_json::from_str(&message).unwrap();
+                                    let req: RpcRequest = match serde_json::from_value(v) {
+                                        Ok(req) => req,
+                                        Err(e) => return Err(malformed(&message, e)),
+                                    };
                                     trace!(
                                         LOGGER,
                                         "{} - Received request type: {}",
@@ -259,9 +634,16 @@ impl Server {
                                     match req.method.as_str() {
                                         // The upstream stratum server has sent us a new job
                                         "job" => {
-                                            let job: JobTemplate =
-                                                serde_json::from_value(req.params.unwrap())
-                                                    .unwrap();
+                                            let job: JobTemplate = match req
+                                                .params
+                                                .map(serde_json::from_value)
+                                            {
+                                                Some(Ok(job)) => job,
+                                                Some(Err(e)) => return Err(malformed(&message, e)),
+                                                None => {
+                                                    return Err(malformed(&message, "no params"))
+                                                }
+                                            };
                                             debug!(
                                                 LOGGER,
                                                 "{} - Setting new job for height {} job_id {} and difficulty {}",
@@ -270,7 +652,7 @@ impl Server {
                                                 job.job_id,
                                                 job.difficulty,
                                             );
-                                            self.job = job;
+                                            self.set_job(job);
                                             return Ok(req.method.clone());
                                         }
                                         _ => {
@@ -291,7 +673,10 @@ impl Server {
                                         }
                                     };
                                 } else {
-                                    let res: RpcResponse = serde_json::from_str(&message).unwrap();
+                                    let res: RpcResponse = match serde_json::from_value(v) {
+                                        Ok(res) => res,
+                                        Err(e) => return Err(malformed(&message, e)),
+                                    };
                                     debug!(LOGGER, "{} - Received response {:?}", self.id, res);
                                     match res.method.as_str() {
                                         // This is a response to a getjobtemplate request made by the pool
@@ -300,21 +685,24 @@ impl Server {
                                             match res.result {
                                                 Some(response) => {
                                                     let job: JobTemplate =
-                                                        serde_json::from_value(response).unwrap();
+                                                        match serde_json::from_value(response) {
+                                                            Ok(job) => job,
+                                                            Err(e) => {
+                                                                return Err(malformed(&message, e))
+                                                            }
+                                                        };
                                                     debug!(
                                                         LOGGER,
                                                         "{} - Setting new job for height {}",
                                                         self.id,
                                                         job.height
                                                     );
-                                                    self.job = job;
+                                                    self.set_job(job);
                                                     return Ok(res.method.clone());
                                                 }
                                                 None => {
                                                     self.error = true;
-                                                    let e: RpcError =
-                                                        serde_json::from_value(res.error.unwrap())
-                                                            .unwrap();
+                                                    let e = upstream_error(res.error);
                                                     // XXX TODO: Send response to the worker
                                                     return Err(e);
                                                 }
@@ -324,103 +712,68 @@ impl Server {
                                         // The pool made this request and it will handle responses
                                         "login" => {
                                             match res.result {
-                                                Some(response) => {
+                                                Some(_) => {
                                                     // Server accepted our login
                                                     return Ok(res.method.clone());
                                                 }
                                                 None => {
                                                     // Server did NOT accept our login
                                                     self.error = true;
-                                                    let e: RpcError =
-                                                        serde_json::from_value(res.error.unwrap())
-                                                            .unwrap();
+                                                    let e = upstream_error(res.error);
                                                     return Err(e);
                                                 }
                                             }
                                         }
                                         "status" => {
-                                            // XXX TODO: Error checking
-                                            self.status =
-                                                serde_json::from_value(res.result.unwrap())
-                                                    .unwrap();
-                                            return Ok(res.method.clone());
-                                        }
-                                        "submit" => {
-                                            // For now only submit here need worder_id(w_id)
-
-                                            // This is a response from the upstream Grin Stratum Server
-                                            // Here, we are accepting responses to requests we sent on behalf of a worker
-                                            // The messages 'id' field contains the worker id this response is for
-                                            // We need to process the responses the pool cares about,
-                                            // The pool made this request and it will handle responses (so return the results back up)
-                                            let mut workers_l = workers.lock().unwrap();
-                                            let decode_string = base64::decode(&res.id);
-                                            // can't be wrong
-                                            let utf8: &[u8] = &decode_string.unwrap();
-
-                                            let w_id_usz: usize;
-                                            let height: i32;
-                                            let job_id: u64;
-                                            let _nonce: u64;
-                                            let edge_bits: u32;
-                                            match ::std::str::from_utf8(utf8) {
-                                                Ok(o) => {
-                                                    let v: Vec<&str> = o.split('+').collect();
-                                                    w_id_usz = match v[0].parse::<usize>() {
-                                                        Ok(value) => value,
-                                                        Err(_) => {
-                                                            let e = RpcError {
-                                                                code: -1,
-                                                                message: "Invalid Worker ID"
-                                                                    .to_string(),
-                                                            };
-                                                            return Err(e);
-                                                        }
-                                                    };
-                                                    // cant be wrong
-                                                    height = v[1].parse::<i32>().unwrap();
-                                                    job_id = v[2].parse::<u64>().unwrap();
-                                                    _nonce = v[3].parse::<u64>().unwrap();
-                                                    edge_bits = v[4].parse::<u32>().unwrap();
+                                            let status: Option<WorkerStatus> = match res.result {
+                                                Some(result) => serde_json::from_value(result).ok(),
+                                                None => None,
+                                            };
+                                            match status {
+                                                Some(status) => {
+                                                    debug!(
+                                                        LOGGER,
+                                                        "{} - Upstream server {} status: height {}, latency {:?}",
+                                                        self.id,
+                                                        self.address(),
+                                                        status.height,
+                                                        self.latency,
+                                                    );
+                                                    self.status = status;
+                                                    return Ok(res.method.clone());
                                                 }
-                                                Err(_) => {
+                                                None => {
                                                     let e = RpcError {
-                                                        code: -1,
-                                                        message: "Invalid Worker ID".to_string(),
+                                                        code: -32600,
+                                                        message: "Invalid status response"
+                                                            .to_string(),
                                                     };
                                                     return Err(e);
                                                 }
                                             }
-                                            info!(
-                                                LOGGER,
-                                                "{}",
-                                                format!(
-                                                    "Successful Split Response ID: [{}, {}, {}, {}, {}]",
-                                                    w_id_usz, height, job_id, _nonce, edge_bits
-                                                )
-                                            );
-                                            // Get the worker index this response is for
-                                            let w_id_o: Option<usize> =
-                                                workers_l.iter().position(|ref i| i.id == w_id_usz);
-                                            let w_id: usize;
-                                            match w_id_o {
-                                                Some(id) => w_id = id,
-                                                _ => {
-                                                    let err_msg = "Null Worker ID".to_string();
-                                                    debug!(LOGGER, "Null Worker ID");
-                                                    self.error = true;
-                                                    let e = RpcError {
-                                                        code: -32600,
-                                                        message: err_msg,
-                                                    };
-                                                    return Err(e);
+                                        }
+                                        "submit" => {
+                                            // This is a response from the upstream Grin Stratum Server
+                                            // to a share we submitted on behalf of a worker.
+                                            // The response 'id' is the pool generated request id
+                                            let request_id = match res.id {
+                                                Value::String(ref id) => id.clone(),
+                                                ref id => id.to_string(),
+                                            };
+                                            let pending = match self.pending.remove(&request_id) {
+                                                Some(pending) => pending,
+                                                None => {
+                                                    // Unknown, or already reported as lost
+                                                    warn!(
+                                                        LOGGER,
+                                                        "{} - Got submit response for unknown request id {}",
+                                                        self.id,
+                                                        res.id
+                                                    );
+                                                    return Ok(res.method.clone());
                                                 }
                                             };
-
-                                            // XXX TODO: Error checking
-                                            debug!(LOGGER, "w_id = {}", w_id);
-                                            let result: SubmitResult;
-                                            match res.result {
+                                            let result = match res.result {
                                                 Some(response) => {
                                                     // The share was accepted
                                                     // response has two type, one is ok the
@@ -428,14 +781,16 @@ impl Server {
                                                     // ok just mean node accept the share
                                                     // block - {HASH} mean valid the share and
                                                     // success
-                                                    debug!(
-                                                        LOGGER,
-                                                        "setting stats for worker id {:?}", res.id
-                                                    );
-                                                    workers_l[w_id].status.accepted += 1;
                                                     debug!(LOGGER, "Server accepted our share");
-                                                    workers_l[w_id].send_ok(res.method.clone());
-                                                    result = SubmitResult::Accept;
+                                                    match block_hash(&response) {
+                                                        Some(hash) => {
+                                                            self.record_block(
+                                                                kafka, &pending, hash,
+                                                            );
+                                                            SubmitResult::Block
+                                                        }
+                                                        None => SubmitResult::Accept,
+                                                    }
                                                 }
                                                 None => {
                                                     // The share was not accepted, check RpcError.code for reason
@@ -443,51 +798,84 @@ impl Server {
                                                     // -32501: Share rejected due to low difficulty
                                                     // -32502: Failed to validate solution
                                                     // -32503: Solution submitted too late
-                                                    // XXX TODO - handle more cases?
-                                                    let e: RpcError =
-                                                        serde_json::from_value(res.error.unwrap())
-                                                            .unwrap();
-                                                    match e.code {
-                                                        -32503 => {
-                                                            workers_l[w_id].status.stale += 1;
-                                                            debug!(
-                                                                LOGGER,
-                                                                "Server rejected share as stale"
-                                                            );
+                                                    let e: Option<RpcError> = match res.error {
+                                                        Some(error) => {
+                                                            serde_json::from_value(error).ok()
                                                         }
-                                                        _ => {
-                                                            workers_l[w_id].status.rejected += 1;
-                                                            debug!(
-                                                                LOGGER,
-                                                                "Server rejected share as invalid"
-                                                            );
+                                                        None => None,
+                                                    };
+                                                    let result = match e {
+                                                        Some(ref e) => {
+                                                            SubmitResult::from_error_code(e.code)
                                                         }
+                                                        None => SubmitResult::Reject,
                                                     };
-                                                    result = SubmitResult::Reject;
+                                                    debug!(
+                                                        LOGGER,
+                                                        "Server rejected share as {:?}: {:?}",
+                                                        result,
+                                                        e
+                                                    );
+                                                    result
                                                 }
                                             };
-
-                                            let worker: &Worker = &workers_l[w_id];
-                                            let share = Share::new(
-                                                job_id,
-                                                self.id.clone(),     // sserver id
-                                                worker.addr.clone(), // worker_addr IP:PORT
-                                                w_id,                // worker id
-                                                worker.status.difficulty, // difficulty
-                                                worker.login(),      // fullname
-                                                result,
-                                                height,
-                                                Utc::now().timestamp() as u32,
-                                            );
-                                            // send share to kafka
-                                            self.kafka.send_data(edge_bits, share);
+                                            // The worker may have disconnected while we waited
+                                            match workers.get_mut(&pending.worker_id) {
+                                                Some(worker) => match result {
+                                                    SubmitResult::Accept | SubmitResult::Block => {
+                                                        worker.status.accepted += 1;
+                                                        let _ = worker.send_ok(
+                                                            res.method.clone(),
+                                                            pending.request_id.clone(),
+                                                        );
+                                                    }
+                                                    _ => {
+                                                        if result == SubmitResult::Stale {
+                                                            worker.status.stale += 1;
+                                                        } else {
+                                                            worker.status.rejected += 1;
+                                                        }
+                                                        let _ = worker.send_error(
+                                                            res.method.clone(),
+                                                            RpcError::for_share(result),
+                                                            pending.request_id.clone(),
+                                                        );
+                                                    }
+                                                },
+                                                None => {
+                                                    debug!(
+                                                        LOGGER,
+                                                        "{} - Worker {} is gone, recording its share anyway",
+                                                        self.id,
+                                                        pending.worker_id
+                                                    );
+                                                }
+                                            }
+                                            self.record_share(kafka, &pending, result);
                                             return Ok(res.method.clone());
                                         }
                                         "keepalive" => {
-                                            // XXX TODO: If its an error
-                                            //      Set error state
-                                            //      Return error message why
-                                            return Ok(res.method.clone());
+                                            if let Some(sent) = self.keepalive_sent.take() {
+                                                self.latency = Some(sent.elapsed());
+                                            }
+                                            match res.error {
+                                                None => {
+                                                    return Ok(res.method.clone());
+                                                }
+                                                Some(error) => {
+                                                    self.error = true;
+                                                    let e: RpcError =
+                                                        match serde_json::from_value(error) {
+                                                            Ok(e) => e,
+                                                            Err(_) => RpcError {
+                                                                code: -32600,
+                                                                message: "Keepalive failed"
+                                                                    .to_string(),
+                                                            },
+                                                        };
+                                                    return Err(e);
+                                                }
+                                            }
                                         }
                                         _ => {
                                             // XXX TODO: Unknown reponse type - log it and continue
@@ -511,7 +899,7 @@ impl Server {
                             } // Not an error, just no messages for us right now
                         }
                     }
-                    Err(e) => {
+                    Err(_) => {
                         self.error = true;
                         let e = RpcError {
                             code: -32600,
@@ -532,3 +920,30 @@ impl Server {
         //return Ok("unknown".to_string());
     }
 }
+
+// The hash of the block solved by a share, from a "block - {HASH}" submit response
+fn block_hash(response: &Value) -> Option<String> {
+    match response.as_str() {
+        Some(r) if r.starts_with("block - ") => Some(r["block - ".len()..].trim().to_string()),
+        _ => None,
+    }
+}
+
+// The error for an upstream message that cannot be understood, it is logged and dropped
+fn malformed<E: ::std::fmt::Display>(message: &str, e: E) -> RpcError {
+    RpcError {
+        code: -32700,
+        message: format!("Malformed upstream message {}: {}", message, e),
+    }
+}
+
+// The error an upstream response carries, or a generic one if it has none
+fn upstream_error(error: Option<Value>) -> RpcError {
+    match error.and_then(|e| serde_json::from_value(e).ok()) {
+        Some(e) => e,
+        None => RpcError {
+            code: -32603,
+            message: "Upstream response has no result or valid error".to_string(),
+        },
+    }
+}
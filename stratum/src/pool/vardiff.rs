@@ -0,0 +1,159 @@
+// Copyright 2018 Blade M. Doyle
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Variable Difficulty
+//!
+//! Tracks a workers share submission rate and retargets its difficulty
+//! toward the configured number of shares per minute
+//!
+
+use std::time::{Duration, Instant};
+
+use pool::config::VarDiffConfig;
+
+// Largest factor the difficulty can move by in a single retarget
+const MAX_RETARGET_FACTOR: f64 = 4.0;
+
+// A worker submitting this many times the expected shares for a whole
+// retarget window is retargeted right away
+const FLOOD_FACTOR: f64 = 2.0;
+
+fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+#[derive(Debug, Clone)]
+pub struct VarDiff {
+    config: VarDiffConfig,
+    min_difficulty: u64,
+    max_difficulty: u64,
+    shares: u64,
+    last_retarget: Instant,
+}
+
+impl VarDiff {
+    /// Creates a new vardiff engine with difficulty bounds for a port
+    pub fn new(config: VarDiffConfig, min_difficulty: u64, max_difficulty: u64) -> VarDiff {
+        VarDiff {
+            config: config,
+            min_difficulty: min_difficulty,
+            max_difficulty: max_difficulty,
+            shares: 0,
+            last_retarget: Instant::now(),
+        }
+    }
+
+    /// Replace the difficulty bounds, None keeps a bound as it is
+    pub fn set_bounds(&mut self, min_difficulty: Option<u64>, max_difficulty: Option<u64>) {
+        if let Some(min_difficulty) = min_difficulty {
+            self.min_difficulty = min_difficulty;
+        }
+        if let Some(max_difficulty) = max_difficulty {
+            self.max_difficulty = max_difficulty;
+        }
+        self.max_difficulty = self.max_difficulty.max(self.min_difficulty);
+    }
+
+    /// Count a valid share submitted by the worker
+    pub fn add_share(&mut self) {
+        self.shares += 1;
+    }
+
+    /// Clamp a difficulty to this engines bounds
+    pub fn clamp(&self, difficulty: u64) -> u64 {
+        difficulty.max(self.min_difficulty).min(self.max_difficulty)
+    }
+
+    /// Check the workers share rate and return the new difficulty if it should change
+    pub fn retarget(&mut self, difficulty: u64) -> Option<u64> {
+        self.retarget_at(difficulty, Instant::now())
+    }
+
+    fn retarget_at(&mut self, difficulty: u64, now: Instant) -> Option<u64> {
+        let elapsed = as_secs_f64(now.duration_since(self.last_retarget));
+        let window = self.config.retarget_time as f64;
+        let expected = self.config.target_shares_per_minute * window / 60.0;
+        // Wait for a full window unless the worker is flooding us
+        if elapsed < window && (self.shares as f64) < expected * FLOOD_FACTOR {
+            return None;
+        }
+        if elapsed <= 0.0 {
+            return None;
+        }
+        let shares_per_minute = self.shares as f64 * 60.0 / elapsed;
+        self.shares = 0;
+        self.last_retarget = now;
+
+        let ratio = shares_per_minute / self.config.target_shares_per_minute;
+        if (ratio - 1.0).abs() <= self.config.variance {
+            return None;
+        }
+        let ratio = ratio.clamp(1.0 / MAX_RETARGET_FACTOR, MAX_RETARGET_FACTOR);
+        let new_difficulty = self.clamp((difficulty as f64 * ratio).round() as u64);
+        if new_difficulty == difficulty {
+            return None;
+        }
+        Some(new_difficulty)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vardiff() -> VarDiff {
+        let config = VarDiffConfig {
+            target_shares_per_minute: 20.0,
+            retarget_time: 60,
+            variance: 0.3,
+        };
+        VarDiff::new(config, 1, 1024)
+    }
+
+    #[test]
+    fn test_no_retarget_within_window() {
+        let mut vd = vardiff();
+        let start = vd.last_retarget;
+        vd.add_share();
+        assert_eq!(vd.retarget_at(8, start + Duration::from_secs(30)), None);
+    }
+
+    #[test]
+    fn test_retarget_up_on_flood() {
+        let mut vd = vardiff();
+        let start = vd.last_retarget;
+        for _ in 0..200 {
+            vd.add_share();
+        }
+        // Flood is caught before the window ends, and limited to one step
+        assert_eq!(vd.retarget_at(8, start + Duration::from_secs(10)), Some(32));
+    }
+
+    #[test]
+    fn test_retarget_down_within_bounds() {
+        let mut vd = vardiff();
+        let start = vd.last_retarget;
+        vd.add_share();
+        assert_eq!(vd.retarget_at(2, start + Duration::from_secs(60)), Some(1));
+    }
+
+    #[test]
+    fn test_no_retarget_within_variance() {
+        let mut vd = vardiff();
+        let start = vd.last_retarget;
+        for _ in 0..22 {
+            vd.add_share();
+        }
+        assert_eq!(vd.retarget_at(8, start + Duration::from_secs(60)), None);
+    }
+}
@@ -19,107 +19,157 @@
 use bufstream::BufStream;
 use serde_json;
 use serde_json::Value;
-use std::collections::HashSet;
-use std::net::TcpStream;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
+use pool::config::{RateLimit, RateLimitConfig};
+use pool::connection::Connection;
 use pool::logger::LOGGER;
-use pool::proto::RpcRequest;
+use pool::naming::WorkerName;
+use pool::options::MinerOptions;
 use pool::proto::{JobTemplate, LoginParams, StratumProtocol, SubmitParams, WorkerStatus};
+use pool::proto::{RpcError, RpcRequest};
+use pool::ratelimit::TokenBucket;
+use pool::reputation::Event;
+use pool::vardiff::VarDiff;
+
+// Bad requests allowed before a worker is disconnected
+pub const DEFAULT_MAX_STRIKES: u32 = 5;
+// Requests handled per worker each event loop turn
+pub const DEFAULT_MAX_MESSAGES_PER_PASS: usize = 32;
 
 // ----------------------------------------
 // Worker Object - a connected stratum client - a miner
 
-fn validate_legal_string(check: &str, legal: &str) -> bool {
-    let legal = legal.replace(" ", "");
-    let legal = legal
-        .split("")
-        .filter(|x| !x.is_empty())
-        .collect::<HashSet<_>>();
-    let check = check.replace(" ", "");
-    let check = check
-        .split("")
-        .filter(|x| !x.is_empty())
-        .collect::<HashSet<_>>();
-    !(check.difference(&legal).collect::<HashSet<_>>().len() > 0)
+// The difficulty each job was sent to a worker with, so a share for a job
+// sent before a retarget is judged by the target the miner was given
+#[derive(Debug, Default)]
+struct JobDifficulties {
+    sent: HashMap<(u64, u64), u64>, // (height, job_id), lowest difficulty sent
 }
 
-// Validate fullname
-fn validate_fullname(login_params: &mut LoginParams) -> bool {
-    let splits = login_params
-        .login
-        .split('.')
-        .map(|s| s.to_string())
-        .collect::<Vec<String>>();
-    let username: &str;
-    let workername: &str;
-    let mut need_reconcat = false;
-    if splits.len() >= 2 {
-        username = splits[0].as_str();
-        workername = splits[1].as_str();
-    } else {
-        need_reconcat = true;
-        username = splits[0].as_str();
-        workername = "__default__";
-    }
-    if validate_username(username) && validate_workername(workername) {
-        if need_reconcat {
-            login_params.login = format!("{}.{}", username, workername);
-        }
-        true
-    } else {
-        false
+impl JobDifficulties {
+    fn record(&mut self, height: u64, job_id: u64, difficulty: u64) {
+        // Shares for earlier blocks are stale anyway
+        self.sent
+            .retain(|&(sent_height, _), _| sent_height >= height);
+        let sent = self.sent.entry((height, job_id)).or_insert(difficulty);
+        *sent = min(*sent, difficulty);
     }
-}
 
-fn validate_username(username: &str) -> bool {
-    if username.is_empty() || username.len() > 20 {
-        false
-    } else {
-        let legal = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ01234567890_";
-        validate_legal_string(username, legal)
+    fn get(&self, height: u64, job_id: u64) -> Option<u64> {
+        self.sent.get(&(height, job_id)).cloned()
     }
 }
 
-fn validate_workername(workername: &str) -> bool {
-    if workername.is_empty() || workername.len() > 18 {
-        false
-    } else {
-        let legal = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ01234567890_.-";
-        validate_legal_string(workername, legal)
-    }
+/// Deadlines a worker session must meet, None for no deadline
+#[derive(Debug, Clone)]
+pub struct SessionTimeouts {
+    pub login: Option<Duration>, // from connecting to logging in
+    pub idle: Option<Duration>,  // between messages
+    pub share: Option<Duration>, // between valid shares, from logging in
 }
 
-#[derive(Debug)]
-pub struct WorkerConfig {}
+// Token buckets for the requests a worker may make, None for unlimited
+#[derive(Debug, Clone)]
+struct RequestLimits {
+    submit: Option<TokenBucket>,
+    status: Option<TokenBucket>,
+    job: Option<TokenBucket>,
+}
+
+/// A session deadline a worker missed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timeout {
+    Login,
+    Idle,
+    Share,
+}
+
+impl Timeout {
+    /// The error the worker is sent before it is disconnected
+    pub fn error(&self) -> RpcError {
+        let message = match *self {
+            Timeout::Login => "Timed out waiting for login",
+            Timeout::Idle => "Timed out waiting for a request",
+            Timeout::Share => "Timed out waiting for a valid share",
+        };
+        RpcError {
+            code: -32000,
+            message: message.to_string(),
+        }
+    }
+}
 
 pub struct Worker {
     pub id: usize,
     login: Option<LoginParams>,
-    stream: BufStream<TcpStream>,
+    account: String, // the account part of the login
+    user_id: i32,    // Pool API user, 0 without login authorization
+    stream: BufStream<Connection>,
     protocol: StratumProtocol,
     error: bool,
-    authenticated: bool,
-    pub status: WorkerStatus,       // Runing totals
-    pub block_status: WorkerStatus, // Totals for current block
-    shares: Vec<SubmitParams>,
+    pub status: WorkerStatus,           // Runing totals
+    pub block_status: WorkerStatus,     // Totals for current block
+    shares: Vec<(Value, SubmitParams)>, // (request id, share)
+    vardiff: Option<VarDiff>,
+    job_difficulties: JobDifficulties,
+    edge_bits: Option<u32>, // The only edge_bits accepted, chosen by the miner
     pub needs_job: bool,
+    job_request: Option<Value>, // id of a getjobtemplate request waiting for a job
+    strikes: u32,               // Bad requests received
+    max_strikes: u32,           // Bad requests allowed before we disconnect
+    events: Vec<Event>,         // Reputation events not yet collected by the pool
+    // (request id, login) waiting for the pool to accept it
+    login_request: Option<(Value, LoginParams)>,
+    port: u64,             // Listen port the worker connected to
+    connected: Instant,    // When the worker connected
+    last_message: Instant, // Last complete message received
+    last_share: Instant,   // Last valid share, or the login
+    rate_limits: RequestLimits,
+    max_messages: usize,  // Requests handled per pass
+    messages_left: usize, // Requests left in this pass
+    unread: bool,         // The last pass ended with requests left unread
     pub addr: String,
 }
 
 impl Worker {
     /// Creates a new Stratum Worker.
-    pub fn new(id: usize, addr: String, stream: BufStream<TcpStream>) -> Worker {
+    pub fn new(id: usize, addr: String, stream: BufStream<Connection>) -> Worker {
         Worker {
             id: id,
             login: None,
+            account: String::new(),
+            user_id: 0,
             stream: stream,
             protocol: StratumProtocol::new(),
             error: false,
-            authenticated: false,
             status: WorkerStatus::new(id.to_string()),
             block_status: WorkerStatus::new(id.to_string()),
             shares: Vec::new(),
-            needs_job: true,
+            vardiff: None,
+            job_difficulties: JobDifficulties::default(),
+            edge_bits: None,
+            needs_job: false, // Until the worker logs in
+            job_request: None,
+            strikes: 0,
+            max_strikes: DEFAULT_MAX_STRIKES,
+            events: Vec::new(),
+            login_request: None,
+            port: 0,
+            connected: Instant::now(),
+            last_message: Instant::now(),
+            last_share: Instant::now(),
+            rate_limits: RequestLimits {
+                submit: None,
+                status: None,
+                job: None,
+            },
+            max_messages: DEFAULT_MAX_MESSAGES_PER_PASS,
+            messages_left: DEFAULT_MAX_MESSAGES_PER_PASS,
+            unread: false,
             addr: addr,
         }
     }
@@ -146,58 +196,368 @@ impl Worker {
         }
     }
 
+    /// The account the worker logged in to, shared by all of its workers
+    pub fn account(&self) -> String {
+        self.account.clone()
+    }
+
+    /// The pool API user the worker logged in as
+    pub fn user_id(&self) -> i32 {
+        self.user_id
+    }
+
+    /// Has the worker logged in
+    pub fn logged_in(&self) -> bool {
+        self.login.is_some()
+    }
+
+    /// The workers IP address
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.addr.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+    }
+
+    /// Set the listen port the worker connected to
+    pub fn set_port(&mut self, port: u64) {
+        self.port = port;
+    }
+
+    pub fn port(&self) -> u64 {
+        self.port
+    }
+
+    /// A login request waiting to be accepted or rejected by the pool
+    pub fn login_request(&self) -> Option<&LoginParams> {
+        match self.login_request {
+            Some((_, ref login_params)) => Some(login_params),
+            None => None,
+        }
+    }
+
+    /// Accept the pending login request under its checked name, and send a
+    /// job to start on
+    pub fn accept_login(&mut self, user_id: i32, name: &WorkerName) -> Result<(), String> {
+        match self.login_request.take() {
+            Some((id, mut login_params)) => {
+                login_params.login = name.login.clone();
+                self.login = Some(login_params);
+                self.account = name.account.clone();
+                self.user_id = user_id;
+                self.needs_job = true;
+                self.last_share = Instant::now();
+                self.send_ok("login".to_string(), id)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Answer the pending login request with an error, the worker may try again
+    pub fn refuse_login(&mut self, error: RpcError) {
+        if let Some((id, login_params)) = self.login_request.take() {
+            warn!(
+                LOGGER,
+                "Worker {} - Refused login {}: {}", self.id, login_params.login, error.message
+            );
+            self.events.push(Event::LoginFailure);
+            let _ = self.send_error("login".to_string(), error, id);
+        }
+    }
+
+    /// Answer the pending login request with an error that is not the
+    /// miner's fault, the worker may try again
+    pub fn defer_login(&mut self, error: RpcError) {
+        if let Some((id, login_params)) = self.login_request.take() {
+            warn!(
+                LOGGER,
+                "Worker {} - Deferred login {}: {}", self.id, login_params.login, error.message
+            );
+            let _ = self.send_error("login".to_string(), error, id);
+        }
+    }
+
+    /// Reject the pending login request and disconnect the worker
+    pub fn reject_login(&mut self, error: RpcError) {
+        if let Some((id, login_params)) = self.login_request.take() {
+            warn!(
+                LOGGER,
+                "Worker {} - Rejected login {}: {}", self.id, login_params.login, error.message
+            );
+            let _ = self.send_error("login".to_string(), error, id);
+        }
+        self.error = true;
+    }
+
+    /// The worker submitted a valid share, only these count toward vardiff
+    pub fn share_accepted(&mut self) {
+        self.last_share = Instant::now();
+        if let Some(ref mut vardiff) = self.vardiff {
+            vardiff.add_share();
+        }
+    }
+
+    /// The first session deadline the worker has missed, if any
+    pub fn missed_deadline(&self, timeouts: &SessionTimeouts, now: Instant) -> Option<Timeout> {
+        let missed = |since: Instant, timeout: Option<Duration>| match timeout {
+            Some(timeout) => now.duration_since(since) >= timeout,
+            None => false,
+        };
+        if self.login.is_none() {
+            if missed(self.connected, timeouts.login) {
+                return Some(Timeout::Login);
+            }
+        } else if missed(self.last_share, timeouts.share) {
+            return Some(Timeout::Share);
+        }
+        if missed(self.last_message, timeouts.idle) {
+            return Some(Timeout::Idle);
+        }
+        None
+    }
+
+    /// Return the reputation events recorded since the last call
+    pub fn take_events(&mut self) -> Vec<Event> {
+        ::std::mem::take(&mut self.events)
+    }
+
+    /// Set the number of messages that may wait to be written to the worker
+    pub fn set_max_queued_messages(&mut self, max_messages: usize) {
+        self.protocol.set_max_queued_messages(max_messages);
+    }
+
+    /// Write queued messages, called when the worker stream becomes writable
+    pub fn flush(&mut self) -> Result<(), String> {
+        let result = self.protocol.flush(&mut self.stream);
+        return self.sent(result);
+    }
+
+    // A worker we can not write to, or that can not keep up, is dropped
+    fn sent(&mut self, result: Result<(), String>) -> Result<(), String> {
+        if let Err(ref e) = result {
+            warn!(LOGGER, "Worker {} - Unable to send: {}", self.id, e);
+            self.error = true;
+        }
+        result
+    }
+
+    /// Set the number of bad requests allowed before the worker is disconnected
+    pub fn set_max_strikes(&mut self, max_strikes: u32) {
+        self.max_strikes = max_strikes;
+    }
+
+    /// Set the largest request accepted from the worker, in bytes
+    pub fn set_max_message_size(&mut self, max_size: usize) {
+        self.protocol.set_max_message_size(max_size);
+    }
+
+    /// Set the number of requests handled each event loop turn
+    pub fn set_max_messages_per_pass(&mut self, max_messages: usize) {
+        self.max_messages = max_messages;
+        self.messages_left = max_messages;
+    }
+
+    /// Set the rate limits for submit, status and job requests
+    pub fn set_rate_limits(&mut self, config: &RateLimitConfig) {
+        let bucket = |limit: &Option<RateLimit>| {
+            limit
+                .as_ref()
+                .map(|limit| TokenBucket::new(limit.rate, limit.burst))
+        };
+        self.rate_limits = RequestLimits {
+            submit: bucket(&config.submit),
+            status: bucket(&config.status),
+            job: bucket(&config.job),
+        };
+    }
+
+    /// Start a new event loop turn, the worker may send more requests
+    pub fn start_pass(&mut self) {
+        self.messages_left = self.max_messages;
+    }
+
+    /// Did the last pass stop with requests left unread
+    pub fn has_unread(&self) -> bool {
+        self.unread
+    }
+
+    // Is this request within the workers rate limits
+    fn allow(&mut self, method: &str) -> bool {
+        let bucket = match method {
+            "submit" => &mut self.rate_limits.submit,
+            "status" => &mut self.rate_limits.status,
+            "getjobtemplate" => &mut self.rate_limits.job,
+            _ => return true,
+        };
+        match *bucket {
+            Some(ref mut bucket) => bucket.take(),
+            None => true,
+        }
+    }
+
+    /// Answer a bad request with an error, and put the worker in error
+    /// state once it has sent too many of them
+    fn strike(&mut self, method: String, error: RpcError, id: Value) -> Result<(), String> {
+        self.strikes += 1;
+        self.events.push(Event::Malformed);
+        warn!(
+            LOGGER,
+            "Worker {} - Bad request ({} of {}): {}",
+            self.id,
+            self.strikes,
+            self.max_strikes,
+            error.message
+        );
+        let _ = self.send_error(method, error.clone(), id);
+        if self.strikes >= self.max_strikes {
+            self.error = true;
+            return Err(error.message);
+        }
+        Ok(())
+    }
+
     /// Set job difficulty
     pub fn set_difficulty(&mut self, new_difficulty: u64) {
         self.status.difficulty = new_difficulty;
     }
 
+    /// Enable variable difficulty for this worker
+    pub fn set_vardiff(&mut self, vardiff: VarDiff) {
+        self.status.difficulty = vardiff.clamp(self.status.difficulty);
+        self.vardiff = Some(vardiff);
+    }
+
+    /// Apply the options the miner sent with its login, already bounded by the pool
+    pub fn set_options(&mut self, options: &MinerOptions) {
+        if let Some(difficulty) = options.difficulty {
+            self.status.difficulty = difficulty;
+        }
+        if options.vardiff == Some(false) {
+            self.vardiff = None;
+        }
+        if let Some(ref mut vardiff) = self.vardiff {
+            vardiff.set_bounds(options.min_difficulty, options.max_difficulty);
+            self.status.difficulty = vardiff.clamp(self.status.difficulty);
+        }
+        if options.edge_bits.is_some() {
+            self.edge_bits = options.edge_bits;
+        }
+    }
+
+    /// The edge_bits the miner chose to submit, if any
+    pub fn edge_bits(&self) -> Option<u32> {
+        self.edge_bits
+    }
+
+    /// The difficulty the worker was asked for with the job a share solves
+    pub fn share_target(&self, share: &SubmitParams) -> u64 {
+        self.job_difficulties
+            .get(share.get_height() as u64, share.job_id)
+            .unwrap_or(self.status.difficulty)
+    }
+
+    /// Retarget the workers difficulty based on its share rate
+    /// Returns true if the difficulty changed and the worker needs a new job
+    pub fn retarget_difficulty(&mut self) -> bool {
+        let new_difficulty = match self.vardiff {
+            None => None,
+            Some(ref mut vardiff) => vardiff.retarget(self.status.difficulty),
+        };
+        match new_difficulty {
+            None => false,
+            Some(difficulty) => {
+                debug!(
+                    LOGGER,
+                    "Worker {} - Retargeting difficulty from {} to {}",
+                    self.id,
+                    self.status.difficulty,
+                    difficulty
+                );
+                self.set_difficulty(difficulty);
+                true
+            }
+        }
+    }
+
     /// Set job height
     pub fn set_height(&mut self, new_height: u64) {
         self.status.height = new_height;
     }
 
-    // XXX TODO: I may need seprate send_job_request() and send_jog_response() methods?
     /// Send a job to the worker
+    /// Answers a pending getjobtemplate request, otherwise pushes the job
     pub fn send_job(&mut self, job: &mut JobTemplate) -> Result<(), String> {
-        trace!(LOGGER, "Worker {} - Sending a job downstream", self.id);
         // Set the difficulty
         job.difficulty = self.status.difficulty;
+        self.job_difficulties
+            .record(job.height, job.job_id, job.difficulty);
         self.needs_job = false;
+        match self.job_request.take() {
+            Some(id) => {
+                trace!(LOGGER, "Worker {} - Sending a job downstream", self.id);
+                let job_value = serde_json::to_value(job).unwrap();
+                let result = self.protocol.send_response(
+                    &mut self.stream,
+                    "getjobtemplate".to_string(),
+                    job_value,
+                    id,
+                );
+                return self.sent(result);
+            }
+            None => self.push_job(job),
+        }
+    }
+
+    /// Push a job the worker did not ask for
+    // Same as the grin stratum server, a "job" request with the id "Stratum"
+    fn push_job(&mut self, job: &JobTemplate) -> Result<(), String> {
+        trace!(LOGGER, "Worker {} - Pushing a job downstream", self.id);
         let job_value = serde_json::to_value(job).unwrap();
-        return self.protocol.send_response(
+        let result = self.protocol.send_request(
             &mut self.stream,
-            "getjobtemplate".to_string(),
-            job_value,
-            self.id,
+            "job".to_string(),
+            Some(job_value),
+            Some("Stratum".to_string()),
         );
+        return self.sent(result);
     }
 
     /// Send worker mining status
-    pub fn send_status(&mut self, status: WorkerStatus) -> Result<(), String> {
+    pub fn send_status(&mut self, status: WorkerStatus, id: Value) -> Result<(), String> {
         trace!(LOGGER, "Worker {} - Sending worker status", self.id);
         let status_value = serde_json::to_value(status).unwrap();
-        return self.protocol.send_response(
-            &mut self.stream,
-            "status".to_string(),
-            status_value,
-            self.id,
-        );
+        let result =
+            self.protocol
+                .send_response(&mut self.stream, "status".to_string(), status_value, id);
+        return self.sent(result);
     }
 
     /// Send OK Response
-    pub fn send_ok(&mut self, method: String) -> Result<(), String> {
+    pub fn send_ok(&mut self, method: String, id: Value) -> Result<(), String> {
         trace!(LOGGER, "Worker {} - sending OK Response", self.id);
-        return self.protocol.send_response(
+        let result = self.protocol.send_response(
             &mut self.stream,
             method,
             serde_json::to_value("ok".to_string()).unwrap(),
+            id,
+        );
+        return self.sent(result);
+    }
+
+    /// Send Error Response
+    pub fn send_error(&mut self, method: String, error: RpcError, id: Value) -> Result<(), String> {
+        trace!(
+            LOGGER,
+            "Worker {} - sending Error Response: {:?}",
             self.id,
+            error
         );
+        let result = self
+            .protocol
+            .send_error_response(&mut self.stream, method, error, id);
+        return self.sent(result);
     }
 
-    /// Return any pending shares from this worker
-    pub fn get_shares(&mut self) -> Result<Option<Vec<SubmitParams>>, String> {
-        if self.shares.len() > 0 {
+    /// Return any pending shares from this worker, with the request id they were submitted with
+    pub fn get_shares(&mut self) -> Result<Option<Vec<(Value, SubmitParams)>>, String> {
+        if !self.shares.is_empty() {
             trace!(
                 LOGGER,
                 "Worker {} - Getting {} shares",
@@ -214,104 +574,149 @@ impl Worker {
     /// Get and process messages from the connected worker
     // Method to handle requests from the downstream worker
     pub fn process_messages(&mut self) -> Result<(), String> {
-        // XXX TODO: With some reasonable rate limiting (like N message per pass)
-        // Read some messages from the upstream
+        // Read available messages from the worker - the event loop only
+        // tells us when new bytes arrive, so if we stop before the stream is
+        // drained the pool calls us again on its next turn
         // Handle each request
-        match self.protocol.get_message(&mut self.stream) {
-            Ok(rpc_msg) => {
-                match rpc_msg {
-                    Some(message) => {
-                        trace!(LOGGER, "Worker {} - Got Message: {:?}", self.id, message);
-                        // let v: Value = serde_json::from_str(&message).unwrap();
-                        let req: RpcRequest = match serde_json::from_str(&message) {
-                            Ok(r) => r,
-                            Err(e) => {
-                                self.error = true;
-                                // XXX TODO: Invalid request
-                                return Err(e.to_string());
-                            }
-                        };
-                        trace!(
-                            LOGGER,
-                            "Worker {} - Received request type: {}",
-                            self.id,
-                            req.method
-                        );
-                        match req.method.as_str() {
-                            "login" => {
-                                debug!(LOGGER, "Worker {} - Accepting Login request", self.id);
-                                let params: Value = match req.params {
-                                    Some(p) => p,
-                                    None => {
-                                        self.error = true;
-                                        // XXX TODO: Invalid request
-                                        return Err("invalid request".to_string());
-                                    }
+        self.unread = false;
+        loop {
+            // Wait for the pool to accept or reject a login before reading on
+            if self.login_request.is_some() {
+                return Ok(());
+            }
+            if self.messages_left == 0 {
+                self.unread = true;
+                return Ok(());
+            }
+            match self.protocol.get_message(&mut self.stream) {
+                Ok(rpc_msg) => {
+                    match rpc_msg {
+                        Some(message) => {
+                            trace!(LOGGER, "Worker {} - Got Message: {:?}", self.id, message);
+                            self.last_message = Instant::now();
+                            self.messages_left -= 1;
+                            // let v: Value = serde_json::from_str(&message).unwrap();
+                            let req: RpcRequest = match serde_json::from_str(&message) {
+                                Ok(r) => r,
+                                Err(e) => {
+                                    let e = RpcError {
+                                        code: -32700,
+                                        message: format!("Parse error: {}", e),
+                                    };
+                                    self.strike("".to_string(), e, Value::Null)?;
+                                    continue;
+                                }
+                            };
+                            trace!(
+                                LOGGER,
+                                "Worker {} - Received request type: {}",
+                                self.id,
+                                req.method
+                            );
+                            if !self.allow(&req.method) {
+                                let e = RpcError {
+                                    code: -32000,
+                                    message: "Rate limit exceeded, slow down".to_string(),
                                 };
-                                let mut login_params: LoginParams =
+                                self.events.push(Event::RateLimited);
+                                let _ = self.send_error(req.method, e, req.id);
+                                continue;
+                            }
+                            match req.method.as_str() {
+                                "login" => {
+                                    debug!(LOGGER, "Worker {} - Accepting Login request", self.id);
+                                    let params = req.params.unwrap_or(Value::Null);
+                                    let login_params: LoginParams =
+                                        match serde_json::from_value(params) {
+                                            Ok(p) => p,
+                                            Err(e) => {
+                                                let e = RpcError {
+                                                    code: -32602,
+                                                    message: format!("Invalid params: {}", e),
+                                                };
+                                                self.strike(req.method, e, req.id)?;
+                                                continue;
+                                            }
+                                        };
+                                    // The pool checks the name and sends the result
+                                    self.login_request = Some((req.id, login_params));
+                                }
+                                "getjobtemplate" => {
+                                    debug!(
+                                        LOGGER,
+                                        "Worker {} - Accepting request for job", self.id
+                                    );
+                                    self.job_request = Some(req.id);
+                                    self.needs_job = true;
+                                }
+                                "submit" => {
+                                    debug!(LOGGER, "Worker {} - Accepting share", self.id);
+                                    let params = req.params.unwrap_or(Value::Null);
                                     match serde_json::from_value(params) {
-                                        Ok(p) => p,
-                                        Err(e) => {
-                                            self.error = true;
-                                            // XXX TODO: Invalid request
-                                            return Err(e.to_string());
+                                        Result::Ok(share) => {
+                                            self.shares.push((req.id, share));
+                                        }
+                                        Result::Err(err) => {
+                                            let e = RpcError {
+                                                code: -32602,
+                                                message: format!("Invalid params: {}", err),
+                                            };
+                                            self.strike(req.method, e, req.id)?;
                                         }
                                     };
-                                // XXX TODO: Validate the login - is it a valid grin wallet address?
-                                if validate_fullname(&mut login_params) {
-                                    self.login = Some(login_params);
-                                    // We accepted the login, send ok result
-                                    self.send_ok(req.method);
-                                } else {
-                                    warn!(
+                                }
+                                "status" => {
+                                    trace!(LOGGER, "Worker {} - Accepting status request", self.id);
+                                    let status = self.status.clone();
+                                    let _ = self.send_status(status, req.id);
+                                }
+                                "keepalive" => {
+                                    trace!(
                                         LOGGER,
-                                        "Worker {} - Is Invalid Name.", login_params.login
+                                        "Worker {} - Accepting keepalive request",
+                                        self.id
                                     );
-                                    return Err("invalid worker name".to_string());
+                                    let _ = self.send_ok(req.method, req.id);
                                 }
-                            }
-                            "getjobtemplate" => {
-                                debug!(LOGGER, "Worker {} - Accepting request for job", self.id);
-                                self.needs_job = true;
-                            }
-                            "submit" => {
-                                debug!(LOGGER, "Worker {} - Accepting share", self.id);
-                                match serde_json::from_value(req.params.unwrap()) {
-                                    Result::Ok(share) => {
-                                        self.shares.push(share);
-                                    }
-                                    Result::Err(err) => {}
-                                };
-                            }
-                            "status" => {
-                                trace!(LOGGER, "Worker {} - Accepting status request", self.id);
-                                let status = self.status.clone();
-                                self.send_status(status);
-                            }
-                            "keepalive" => {
-                                trace!(LOGGER, "Worker {} - Accepting keepalive request", self.id);
-                                self.send_ok(req.method);
-                            }
-                            _ => {
-                                warn!(
-                                    LOGGER,
-                                    "Worker {} - Unknown request: {}",
-                                    self.id,
-                                    req.method.as_str()
-                                );
-                                self.error = true;
-                                return Err("Unknown request".to_string());
-                            }
-                        };
+                                _ => {
+                                    let e = RpcError {
+                                        code: -32601,
+                                        message: format!("Method not found: {}", req.method),
+                                    };
+                                    self.strike(req.method, e, req.id)?;
+                                }
+                            };
+                        }
+                        None => return Ok(()), // Not an error, just no more messages for us right now
                     }
-                    None => {} // Not an error, just no messages for us right now
                 }
-            }
-            Err(e) => {
-                self.error = true;
-                return Err(e.to_string());
+                Err(e) => {
+                    self.error = true;
+                    return Err(e.to_string());
+                }
             }
         }
-        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_job_difficulties() {
+        let mut jobs = JobDifficulties::default();
+        jobs.record(100, 0, 1000);
+        // Retargeted before the share for job 0 arrives, the job is sent
+        // again with the new difficulty
+        jobs.record(100, 0, 4000);
+        jobs.record(100, 1, 4000);
+        assert_eq!(jobs.get(100, 0), Some(1000));
+        assert_eq!(jobs.get(100, 1), Some(4000));
+        assert_eq!(jobs.get(100, 2), None);
+        // A new block forgets the old jobs
+        jobs.record(101, 0, 4000);
+        assert_eq!(jobs.get(100, 0), None);
+        assert_eq!(jobs.get(101, 0), Some(4000));
     }
 }